@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use crate::{
+    api, device::ESPHomeDevice, error::DeviceError, handle::DeviceHandle, model::MessageType, subscription::{OverflowPolicy, Subscription}
+};
+
+/// `SubscribeBluetoothLEAdvertisementsRequest.flags`: send raw (batched) advertisements if supported
+pub const BLE_SUBSCRIBE_FLAG_RAW_ADVERTISEMENTS: i32 = 1;
+
+/// A parsed BLE advertisement received by a bluetooth proxy
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BleAdvertisement {
+    /// MAC address (lower 48 bits)
+    pub address: u64,
+    /// 0 = public, 1 = random
+    pub address_type: u32,
+    pub rssi: i32,
+    pub name: Option<String>,
+    pub tx_power: Option<i8>,
+    /// 128-bit lowercase UUIDs (ex. `0000180f-0000-1000-8000-00805f9b34fb`)
+    pub service_uuids: Vec<String>,
+    /// service UUID (128-bit) -> data
+    pub service_data: HashMap<String, Vec<u8>>,
+    /// company id -> data
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl BleAdvertisement {
+    /// address formatted as `AA:BB:CC:DD:EE:FF`
+    pub fn mac(&self) -> String {
+        format_mac(self.address)
+    }
+
+    /// Parse raw advertising data (AD structures of the advertisement + scan response)
+    pub fn from_raw(address: u64, address_type: u32, rssi: i32, data: &[u8]) -> Self {
+        let mut adv = BleAdvertisement { address, address_type, rssi, ..Default::default() };
+        let mut short_name = None;
+        let mut rest = data;
+        while let [len, tail @ ..] = rest {
+            let len = *len as usize;
+            if len == 0 || tail.len() < len {
+                break;
+            }
+            let (ad_type, value) = (tail[0], &tail[1..len]);
+            rest = &tail[len..];
+            match ad_type {
+                0x02 | 0x03 => adv.service_uuids.extend(value.chunks_exact(2).map(uuid_from_le)),
+                0x04 | 0x05 => adv.service_uuids.extend(value.chunks_exact(4).map(uuid_from_le)),
+                0x06 | 0x07 => adv.service_uuids.extend(value.chunks_exact(16).map(uuid_from_le)),
+                0x08 => short_name = Some(String::from_utf8_lossy(value).into_owned()),
+                0x09 => adv.name = Some(String::from_utf8_lossy(value).into_owned()),
+                0x0A if !value.is_empty() => adv.tx_power = Some(value[0] as i8),
+                0x16 | 0x20 | 0x21 => {
+                    let uuid_len = match ad_type { 0x16 => 2, 0x20 => 4, _ => 16 };
+                    if value.len() >= uuid_len {
+                        adv.service_data.insert(uuid_from_le(&value[..uuid_len]), value[uuid_len..].to_vec());
+                    }
+                }
+                0xFF if value.len() >= 2 => {
+                    adv.manufacturer_data.insert(u16::from_le_bytes([value[0], value[1]]), value[2..].to_vec());
+                }
+                _ => {}
+            }
+        }
+        if adv.name.is_none() {
+            adv.name = short_name;
+        }
+        adv
+    }
+}
+
+impl From<api::BluetoothLeAdvertisementResponse> for BleAdvertisement {
+    fn from(value: api::BluetoothLeAdvertisementResponse) -> Self {
+        // before api 1.7 data was sent as one u32 per byte
+        let data = |d: api::BluetoothServiceData| match d.data.is_empty() {
+            true => d.legacy_data.into_iter().map(|b| b as u8).collect(),
+            false => d.data,
+        };
+        Self {
+            address: value.address,
+            address_type: value.address_type,
+            rssi: value.rssi,
+            name: (!value.name.is_empty()).then(|| String::from_utf8_lossy(&value.name).into_owned()),
+            tx_power: None,
+            service_uuids: value.service_uuids.iter().map(|u| normalize_uuid(u)).collect(),
+            service_data: value.service_data.into_iter()
+                .map(|d| (normalize_uuid(&d.uuid), data(d)))
+                .collect(),
+            manufacturer_data: value.manufacturer_data.into_iter()
+                .filter_map(|d| Some((u16::from_str_radix(d.uuid.trim_start_matches("0x"), 16).ok()?, data(d))))
+                .collect(),
+        }
+    }
+}
+
+pub fn format_mac(address: u64) -> String {
+    let b = address.to_be_bytes();
+    format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", b[2], b[3], b[4], b[5], b[6], b[7])
+}
+
+/// 16/32/128-bit little endian UUID to a 128-bit UUID string
+fn uuid_from_le(bytes: &[u8]) -> String {
+    match bytes.len() {
+        2 => base_uuid(u16::from_le_bytes([bytes[0], bytes[1]]) as u32),
+        4 => base_uuid(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        _ => {
+            let b: Vec<u8> = bytes.iter().rev().copied().collect();
+            format!(
+                "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+            )
+        }
+    }
+}
+
+fn base_uuid(short: u32) -> String {
+    format!("{short:08x}-0000-1000-8000-00805f9b34fb")
+}
+
+/// ESPHome sends 16/32-bit UUIDs as `0x180F`
+pub(crate) fn normalize_uuid(uuid: &str) -> String {
+    match uuid.strip_prefix("0x").map(|hex| u32::from_str_radix(hex, 16)) {
+        Some(Ok(short)) => base_uuid(short),
+        _ => uuid.to_lowercase(),
+    }
+}
+
+impl ESPHomeDevice {
+    /// Request BLE advertisements from a bluetooth proxy.
+    /// Returns a stream of advertisements, buffering up to `buffer_size` (handled according to `policy` when full).
+    /// The device is unsubscribed once all subscriptions are dropped.
+    pub async fn subscribe_bluetooth_advertisements(&mut self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<BleAdvertisement>, DeviceError> {
+        let sub = self.ble_adv_tx.subscribe(buffer_size, policy);
+        if !self.ble_adv_subscribed {
+            self.send(
+                MessageType::SubscribeBluetoothLEAdvertisementsRequest,
+                &api::SubscribeBluetoothLeAdvertisementsRequest { flags: BLE_SUBSCRIBE_FLAG_RAW_ADVERTISEMENTS },
+            ).await?;
+            self.ble_adv_subscribed = true;
+        }
+        Ok(sub)
+    }
+
+    /// Stop receiving advertisements, ending all advertisement subscriptions
+    pub async fn unsubscribe_bluetooth_advertisements(&mut self) -> Result<(), DeviceError> {
+        self.ble_adv_tx.close();
+        self.ble_adv_subscribed = false;
+        self.send(
+            MessageType::UnsubscribeBluetoothLEAdvertisementsRequest,
+            &api::UnsubscribeBluetoothLeAdvertisementsRequest {},
+        ).await
+    }
+
+    pub(crate) async fn on_bluetooth_advertisements(&mut self, advertisements: Vec<BleAdvertisement>) -> Result<(), DeviceError> {
+        if !self.ble_adv_tx.has_subscribers() {
+            if self.ble_adv_subscribed {
+                self.unsubscribe_bluetooth_advertisements().await?;
+            }
+            return Ok(());
+        }
+        for adv in advertisements {
+            self.ble_adv_tx.send(adv).await;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceHandle {
+    /// see `ESPHomeDevice::subscribe_bluetooth_advertisements`
+    pub async fn subscribe_bluetooth_advertisements(&self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<BleAdvertisement>, DeviceError> {
+        self.call(move |dev| Box::pin(dev.subscribe_bluetooth_advertisements(buffer_size, policy))).await?
+    }
+
+    /// Stop receiving advertisements, ending all advertisement subscriptions
+    pub async fn unsubscribe_bluetooth_advertisements(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.unsubscribe_bluetooth_advertisements())).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::test_util::*;
+
+    #[test]
+    fn raw() {
+        let data = [
+            0x02, 0x01, 0x06, // flags
+            0x05, 0x03, 0x0F, 0x18, 0x0A, 0x18, // 16-bit uuids
+            0x05, 0x08, b'S', b'h', b'r', b't', // short name
+            0x02, 0x0A, 0xF4, // tx power -12
+            0x05, 0x16, 0x0F, 0x18, 0x55, 0x01, // battery service data
+            0x07, 0xFF, 0x4C, 0x00, 0x02, 0x15, 0xAA, 0xBB, // apple manufacturer data
+            0x06, 0x09, b'S', b'h', b'o', b'r', b't', // complete name
+            0x00, 0x00, // padding
+        ];
+        let adv = BleAdvertisement::from_raw(0xA4C1_3811_2233, 0, -70, &data);
+        assert_eq!(adv.mac(), "A4:C1:38:11:22:33");
+        assert_eq!(adv.name.as_deref(), Some("Short"));
+        assert_eq!(adv.tx_power, Some(-12));
+        assert_eq!(adv.service_uuids, ["0000180f-0000-1000-8000-00805f9b34fb", "0000180a-0000-1000-8000-00805f9b34fb"]);
+        assert_eq!(adv.service_data["0000180f-0000-1000-8000-00805f9b34fb"], [0x55, 0x01]);
+        assert_eq!(adv.manufacturer_data[&0x004C], [0x02, 0x15, 0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn raw_truncated_and_128_bit() {
+        let mut data = vec![0x11, 0x07];
+        data.extend((0u8..16).rev());
+        data.extend([0x09, 0xFF, 0x01]); // truncated
+        let adv = BleAdvertisement::from_raw(1, 1, -40, &data);
+        assert_eq!(adv.service_uuids, ["00010203-0405-0607-0809-0a0b0c0d0e0f"]);
+        assert!(adv.manufacturer_data.is_empty());
+    }
+
+    #[test]
+    fn legacy() {
+        let adv: BleAdvertisement = api::BluetoothLeAdvertisementResponse {
+            address: 0x1122_3344_5566,
+            name: b"sensor".to_vec(),
+            rssi: -80,
+            service_uuids: vec!["0x181A".to_string(), "0000FE95-0000-1000-8000-00805F9B34FB".to_string()],
+            service_data: vec![api::BluetoothServiceData { uuid: "0xFE95".to_string(), legacy_data: vec![1, 2], data: vec![] }],
+            manufacturer_data: vec![api::BluetoothServiceData { uuid: "0x004C".to_string(), legacy_data: vec![], data: vec![3] }],
+            address_type: 1,
+        }.into();
+        assert_eq!(adv.name.as_deref(), Some("sensor"));
+        assert_eq!(adv.service_uuids, ["0000181a-0000-1000-8000-00805f9b34fb", "0000fe95-0000-1000-8000-00805f9b34fb"]);
+        assert_eq!(adv.service_data["0000fe95-0000-1000-8000-00805f9b34fb"], [1, 2]);
+        assert_eq!(adv.manufacturer_data[&0x004C], [3]);
+    }
+
+    #[tokio::test]
+    async fn bluetooth_advertisements() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let mut advs = timeout(TIMEOUT, handle.subscribe_bluetooth_advertisements(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+        let req: api::SubscribeBluetoothLeAdvertisementsRequest = timeout(TIMEOUT, mock.wait_for(MessageType::SubscribeBluetoothLEAdvertisementsRequest)).await.unwrap().unwrap();
+        assert_eq!(req.flags, 1);
+        mock.push(MessageType::BluetoothLEAdvertisementResponse, &api::BluetoothLeAdvertisementResponse {
+            address: 1,
+            rssi: -60,
+            manufacturer_data: vec![api::BluetoothServiceData { uuid: "0x004C".to_string(), data: vec![1], ..Default::default() }],
+            ..Default::default()
+        });
+        mock.push(MessageType::BluetoothLERawAdvertisementsResponse, &api::BluetoothLeRawAdvertisementsResponse {
+            advertisements: vec![
+                api::BluetoothLeRawAdvertisement { address: 2, rssi: -70, address_type: 1, data: vec![0x03, 0x09, b'h', b'i'] },
+                api::BluetoothLeRawAdvertisement { address: 3, rssi: -80, address_type: 0, data: vec![] },
+            ],
+        });
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(timeout(TIMEOUT, advs.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(received.iter().map(|a| (a.address, a.rssi)).collect::<Vec<_>>(), [(1, -60), (2, -70), (3, -80)]);
+        assert_eq!(received[0].manufacturer_data[&0x004C], [1]);
+        assert_eq!(received[1].name.as_deref(), Some("hi"));
+
+        //unsubscribes once nobody is listening
+        drop(advs);
+        mock.push(MessageType::BluetoothLERawAdvertisementsResponse, &api::BluetoothLeRawAdvertisementsResponse::default());
+        let _: api::UnsubscribeBluetoothLeAdvertisementsRequest = timeout(TIMEOUT, mock.wait_for(MessageType::UnsubscribeBluetoothLEAdvertisementsRequest)).await.unwrap().unwrap();
+        assert!(handle.is_connected().await.unwrap());
+    }
+}
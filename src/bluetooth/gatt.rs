@@ -0,0 +1,668 @@
+use bytes::Bytes;
+use std::{collections::{HashMap, VecDeque}, fmt, time::Duration};
+use prost::Message;
+use thiserror::Error;
+use tokio::{sync::oneshot, time::timeout};
+use crate::{
+    api, bluetooth::{advertisement::format_mac, proxy::BluetoothProxyFeatures}, device::ESPHomeDevice, error::DeviceError, handle::DeviceHandle, model::MessageType, subscription::{Fanout, OverflowPolicy, Subscription}
+};
+
+/// How long to wait for the proxy to answer a GATT request (connecting can take a while)
+pub const GATT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Status codes of failed GATT operations (ATT errors and ESP-IDF GATT errors)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GattStatus {
+    NotConnected,
+    InvalidHandle,
+    ReadNotPermitted,
+    WriteNotPermitted,
+    InvalidPdu,
+    InsufficientAuthentication,
+    RequestNotSupported,
+    InvalidOffset,
+    InsufficientAuthorization,
+    PrepareQueueFull,
+    AttributeNotFound,
+    AttributeNotLong,
+    InsufficientKeySize,
+    InvalidAttributeLength,
+    Unlikely,
+    InsufficientEncryption,
+    UnsupportedGroupType,
+    InsufficientResources,
+    NoResources,
+    InternalError,
+    WrongState,
+    DbFull,
+    Busy,
+    Error,
+    IllegalParameter,
+    AuthFailed,
+    Congested,
+    Other(i32),
+}
+
+impl From<i32> for GattStatus {
+    fn from(code: i32) -> Self {
+        match code {
+            -1 => Self::NotConnected,
+            0x01 => Self::InvalidHandle,
+            0x02 => Self::ReadNotPermitted,
+            0x03 => Self::WriteNotPermitted,
+            0x04 => Self::InvalidPdu,
+            0x05 => Self::InsufficientAuthentication,
+            0x06 => Self::RequestNotSupported,
+            0x07 => Self::InvalidOffset,
+            0x08 => Self::InsufficientAuthorization,
+            0x09 => Self::PrepareQueueFull,
+            0x0a => Self::AttributeNotFound,
+            0x0b => Self::AttributeNotLong,
+            0x0c => Self::InsufficientKeySize,
+            0x0d => Self::InvalidAttributeLength,
+            0x0e => Self::Unlikely,
+            0x0f => Self::InsufficientEncryption,
+            0x10 => Self::UnsupportedGroupType,
+            0x11 => Self::InsufficientResources,
+            0x80 => Self::NoResources,
+            0x81 => Self::InternalError,
+            0x82 => Self::WrongState,
+            0x83 => Self::DbFull,
+            0x84 => Self::Busy,
+            0x85 => Self::Error,
+            0x87 => Self::IllegalParameter,
+            0x89 => Self::AuthFailed,
+            0x8f => Self::Congested,
+            _ => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for GattStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Other(code) => write!(f, "unknown error {code:#x}"),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum GattError {
+    #[error("connecting to {} failed `{}`", format_mac(*.0), .1)]
+    ConnectFailed(u64, GattStatus),
+    #[error("{} disconnected", format_mac(*.0))]
+    Disconnected(u64),
+    #[error("{} handle {handle:#x} failed `{status}`", format_mac(*.address))]
+    Failed { address: u64, handle: u32, status: GattStatus },
+    #[error("{} {operation} failed `{status}`", format_mac(*.address))]
+    DeviceRequestFailed { address: u64, operation: &'static str, status: GattStatus },
+    #[error("no proxy with a free slot has seen {}", format_mac(*.0))]
+    NoProxyAvailable(u64),
+    #[error("no response from proxy within {0:?}")]
+    Timeout(Duration),
+    #[error("{} unexpected response to {operation}", format_mac(*.address))]
+    UnexpectedReply { address: u64, operation: &'static str },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GattDescriptor {
+    pub uuid: String,
+    pub handle: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GattCharacteristic {
+    pub uuid: String,
+    pub handle: u32,
+    /// bitflags (0x02 read, 0x04 write without response, 0x08 write, 0x10 notify, 0x20 indicate)
+    pub properties: u32,
+    pub descriptors: Vec<GattDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GattService {
+    pub uuid: String,
+    pub handle: u32,
+    pub characteristics: Vec<GattCharacteristic>,
+}
+
+impl GattService {
+    pub fn characteristic(&self, uuid: &str) -> Option<&GattCharacteristic> {
+        self.characteristics.iter().find(|c| c.uuid.eq_ignore_ascii_case(uuid))
+    }
+}
+
+/// uuids are sent as two u64 (high, low)
+fn uuid_from_u64s(uuid: &[u64]) -> String {
+    let (high, low) = (uuid.first().copied().unwrap_or(0), uuid.get(1).copied().unwrap_or(0));
+    let v = ((high as u128) << 64) | low as u128;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        (v >> 96) as u32, (v >> 80) as u16, (v >> 64) as u16, (v >> 48) as u16, v & 0xffff_ffff_ffff
+    )
+}
+
+impl From<api::BluetoothGattService> for GattService {
+    fn from(value: api::BluetoothGattService) -> Self {
+        Self {
+            uuid: uuid_from_u64s(&value.uuid),
+            handle: value.handle,
+            characteristics: value.characteristics.into_iter().map(|c| GattCharacteristic {
+                uuid: uuid_from_u64s(&c.uuid),
+                handle: c.handle,
+                properties: c.properties,
+                descriptors: c.descriptors.into_iter().map(|d| GattDescriptor {
+                    uuid: uuid_from_u64s(&d.uuid),
+                    handle: d.handle,
+                }).collect(),
+            }).collect(),
+        }
+    }
+}
+
+/// A notification/indication from a characteristic
+#[derive(Debug, Clone)]
+pub struct GattNotification {
+    pub address: u64,
+    pub handle: u32,
+    pub data: Bytes,
+}
+
+/// What a pending request is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum GattOp {
+    Connect,
+    Disconnect,
+    Services,
+    Read(u32),
+    Write(u32),
+    ReadDescriptor(u32),
+    WriteDescriptor(u32),
+    Notify(u32),
+    Pair,
+    Unpair,
+    ClearCache,
+}
+
+impl GattOp {
+    /// the attribute handle the op targets, if any
+    fn handle(self) -> Option<u32> {
+        match self {
+            Self::Read(handle) | Self::Write(handle) | Self::ReadDescriptor(handle) | Self::WriteDescriptor(handle) | Self::Notify(handle) => Some(handle),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum GattReply {
+    Connected { mtu: u32 },
+    Services(Vec<GattService>),
+    Data(Bytes),
+    Done,
+}
+
+type Waiter = oneshot::Sender<Result<GattReply, GattError>>;
+
+/// GATT requests waiting for a response from the proxy, per address in the order they were sent
+#[derive(Default)]
+pub(crate) struct GattState {
+    pending: HashMap<u64, VecDeque<(GattOp, Waiter)>>,
+    /// services received so far, until GetServicesDone
+    services: HashMap<u64, Vec<GattService>>,
+    notify_tx: HashMap<u64, Fanout<GattNotification>>,
+    /// address -> mtu
+    pub(crate) connections: HashMap<u64, u32>,
+}
+
+impl GattState {
+    pub(crate) fn register(&mut self, address: u64, op: GattOp) -> oneshot::Receiver<Result<GattReply, GattError>> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.entry(address).or_default().push_back((op, tx));
+        rx
+    }
+
+    /// answer the oldest waiter for `address` whose op matches
+    fn resolve(&mut self, address: u64, matches: impl Fn(GattOp) -> bool, reply: Result<GattReply, GattError>) {
+        if let Some(waiters) = self.pending.get_mut(&address) {
+            // drop waiters that gave up (ex. timed out)
+            waiters.retain(|(_, waiter)| !waiter.is_closed());
+            if let Some(i) = waiters.iter().position(|(op, _)| matches(*op)) {
+                let (_, waiter) = waiters.remove(i).unwrap();
+                let _ = waiter.send(reply);
+            }
+            if waiters.is_empty() {
+                self.pending.remove(&address);
+            }
+        }
+    }
+
+    /// fail everything pending for `address` (or all if None), ending notification streams
+    fn fail_all(&mut self, address: Option<u64>) {
+        let addresses: Vec<_> = self.pending.keys().filter(|a| address.is_none_or(|address| **a == address)).copied().collect();
+        for address in addresses {
+            for (_, waiter) in self.pending.remove(&address).unwrap_or_default() {
+                let _ = waiter.send(Err(GattError::Disconnected(address)));
+            }
+        }
+        match address {
+            Some(address) => {
+                self.services.remove(&address);
+                self.notify_tx.remove(&address);
+                self.connections.remove(&address);
+            }
+            None => {
+                self.services.clear();
+                self.notify_tx.clear();
+                self.connections.clear();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_notify_subscription(&self, address: u64) -> bool {
+        self.notify_tx.contains_key(&address)
+    }
+
+    /// called when the connection to the proxy is lost
+    pub(crate) fn reset(&mut self) {
+        self.fail_all(None);
+    }
+}
+
+impl ESPHomeDevice {
+    /// Route GATT/device responses from the proxy to the waiting requests
+    pub(crate) async fn handle_gatt_message(&mut self, msg_type: MessageType, msg: bytes::BytesMut) -> Result<(), DeviceError> {
+        let gatt = &mut self.gatt;
+        match msg_type {
+            MessageType::BluetoothDeviceConnectionResponse => {
+                let res = api::BluetoothDeviceConnectionResponse::decode(msg)?;
+                if res.connected {
+                    gatt.connections.insert(res.address, res.mtu);
+                    gatt.resolve(res.address, |op| op == GattOp::Connect, Ok(GattReply::Connected { mtu: res.mtu }));
+                }
+                else {
+                    let error = match res.error {
+                        0 => GattError::Disconnected(res.address),
+                        code => GattError::ConnectFailed(res.address, code.into()),
+                    };
+                    gatt.resolve(res.address, |op| op == GattOp::Connect, Err(error));
+                    gatt.resolve(res.address, |op| op == GattOp::Disconnect, Ok(GattReply::Done));
+                    gatt.fail_all(Some(res.address));
+                }
+            }
+            MessageType::BluetoothGATTGetServicesResponse => {
+                let res = api::BluetoothGattGetServicesResponse::decode(msg)?;
+                gatt.services.entry(res.address).or_default().extend(res.services.into_iter().map(GattService::from));
+            }
+            MessageType::BluetoothGATTGetServicesDoneResponse => {
+                let res = api::BluetoothGattGetServicesDoneResponse::decode(msg)?;
+                let services = gatt.services.remove(&res.address).unwrap_or_default();
+                gatt.resolve(res.address, |op| op == GattOp::Services, Ok(GattReply::Services(services)));
+            }
+            MessageType::BluetoothGATTReadResponse => {
+                let res = api::BluetoothGattReadResponse::decode(msg)?;
+                // characteristic and descriptor reads share the response
+                let handle = res.handle;
+                gatt.resolve(res.address, |op| matches!(op, GattOp::Read(h) | GattOp::ReadDescriptor(h) if h == handle), Ok(GattReply::Data(res.data.into())));
+            }
+            MessageType::BluetoothGATTWriteResponse => {
+                let res = api::BluetoothGattWriteResponse::decode(msg)?;
+                let handle = res.handle;
+                gatt.resolve(res.address, |op| matches!(op, GattOp::Write(h) | GattOp::WriteDescriptor(h) if h == handle), Ok(GattReply::Done));
+            }
+            MessageType::BluetoothGATTNotifyResponse => {
+                let res = api::BluetoothGattNotifyResponse::decode(msg)?;
+                let handle = res.handle;
+                gatt.resolve(res.address, |op| op == GattOp::Notify(handle), Ok(GattReply::Done));
+            }
+            MessageType::BluetoothGATTNotifyDataResponse => {
+                let res = api::BluetoothGattNotifyDataResponse::decode(msg)?;
+                if let Some(tx) = gatt.notify_tx.get_mut(&res.address) {
+                    tx.send(GattNotification { address: res.address, handle: res.handle, data: res.data.into() }).await;
+                }
+            }
+            MessageType::BluetoothGATTErrorResponse => {
+                let res = api::BluetoothGattErrorResponse::decode(msg)?;
+                let (address, handle) = (res.address, res.handle);
+                // the error doesn't say which request failed, only the handle, so fail the oldest one on it
+                let error = GattError::Failed { address, handle, status: res.error.into() };
+                gatt.resolve(address, |op| op.handle() == Some(handle), Err(error));
+            }
+            MessageType::BluetoothDevicePairingResponse => {
+                let res = api::BluetoothDevicePairingResponse::decode(msg)?;
+                gatt.resolve(res.address, |op| op == GattOp::Pair, device_request_result(res.address, "pairing", res.paired, res.error));
+            }
+            MessageType::BluetoothDeviceUnpairingResponse => {
+                let res = api::BluetoothDeviceUnpairingResponse::decode(msg)?;
+                gatt.resolve(res.address, |op| op == GattOp::Unpair, device_request_result(res.address, "unpairing", res.success, res.error));
+            }
+            MessageType::BluetoothDeviceClearCacheResponse => {
+                let res = api::BluetoothDeviceClearCacheResponse::decode(msg)?;
+                gatt.resolve(res.address, |op| op == GattOp::ClearCache, device_request_result(res.address, "clearing cache", res.success, res.error));
+            }
+            _ => return Err(DeviceError::UnknownIncomingMessageType(msg_type)),
+        }
+        Ok(())
+    }
+}
+
+fn device_request_result(address: u64, operation: &'static str, success: bool, error: i32) -> Result<GattReply, GattError> {
+    match success {
+        true => Ok(GattReply::Done),
+        false => Err(GattError::DeviceRequestFailed { address, operation, status: error.into() }),
+    }
+}
+
+/// Active GATT connections through a bluetooth proxy (a spawned device)
+#[derive(Clone)]
+pub struct BluetoothProxy {
+    handle: DeviceHandle,
+    /// how long to wait for each response
+    pub timeout: Duration,
+}
+
+impl DeviceHandle {
+    pub fn bluetooth_proxy(&self) -> BluetoothProxy {
+        BluetoothProxy { handle: self.clone(), timeout: GATT_TIMEOUT }
+    }
+}
+
+impl BluetoothProxy {
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// send `msg`, then wait for the proxy to answer `op` for `address`
+    async fn request<M: Message + Send + 'static>(&self, address: u64, op: GattOp, msg_type: MessageType, msg: M) -> Result<GattReply, DeviceError> {
+        let rx = self.handle.call(move |dev| Box::pin(async move {
+            let rx = dev.gatt.register(address, op);
+            dev.send(msg_type, &msg).await?;
+            Ok::<_, DeviceError>(rx)
+        })).await??;
+        match timeout(self.timeout, rx).await {
+            Ok(Ok(reply)) => Ok(reply?),
+            // device task dropped the request (ex. lost connection to the proxy)
+            Ok(Err(_)) => Err(GattError::Disconnected(address).into()),
+            Err(_) => Err(GattError::Timeout(self.timeout).into()),
+        }
+    }
+
+    async fn device_request(&self, address: u64, op: GattOp, request_type: api::BluetoothDeviceRequestType, address_type: Option<u32>) -> Result<GattReply, DeviceError> {
+        self.request(address, op, MessageType::BluetoothDeviceRequest, api::BluetoothDeviceRequest {
+            address,
+            request_type: request_type.into(),
+            has_address_type: address_type.is_some(),
+            address_type: address_type.unwrap_or_default(),
+        }).await
+    }
+
+    /// Connect to the BLE device with MAC `address` (services are cached by the proxy if supported).
+    /// `address_type` (0 = public, 1 = random) is needed if the proxy hasn't seen an advertisement yet.
+    pub async fn connect(&self, address: u64, address_type: Option<u32>) -> Result<GattConnection, DeviceError> {
+        let features = self.handle.bluetooth_proxy_features().await?;
+        let request_type = if features.contains(BluetoothProxyFeatures::REMOTE_CACHING) {
+            api::BluetoothDeviceRequestType::ConnectV3WithCache
+        }
+        else if features.contains(BluetoothProxyFeatures::ACTIVE_CONNECTIONS) {
+            api::BluetoothDeviceRequestType::ConnectV3WithoutCache
+        }
+        else {
+            api::BluetoothDeviceRequestType::Connect
+        };
+        match self.device_request(address, GattOp::Connect, request_type, address_type).await? {
+            GattReply::Connected { mtu } => Ok(GattConnection { proxy: self.clone(), address, mtu }),
+            _ => Err(GattError::UnexpectedReply { address, operation: "connect" }.into()),
+        }
+    }
+
+    /// Currently connected addresses and their MTU
+    pub async fn connections(&self) -> Result<HashMap<u64, u32>, DeviceError> {
+        self.handle.call(|dev| Box::pin(async move { dev.gatt.connections.clone() })).await
+    }
+}
+
+/// A GATT connection to a BLE device through a proxy
+#[derive(Clone)]
+pub struct GattConnection {
+    proxy: BluetoothProxy,
+    pub address: u64,
+    pub mtu: u32,
+}
+
+impl GattConnection {
+    pub fn mac(&self) -> String {
+        format_mac(self.address)
+    }
+
+    pub async fn disconnect(&self) -> Result<(), DeviceError> {
+        let request_type = api::BluetoothDeviceRequestType::Disconnect;
+        self.proxy.device_request(self.address, GattOp::Disconnect, request_type, None).await?;
+        Ok(())
+    }
+
+    pub async fn services(&self) -> Result<Vec<GattService>, DeviceError> {
+        let req = api::BluetoothGattGetServicesRequest { address: self.address };
+        match self.proxy.request(self.address, GattOp::Services, MessageType::BluetoothGATTGetServicesRequest, req).await? {
+            GattReply::Services(services) => Ok(services),
+            _ => Err(GattError::UnexpectedReply { address: self.address, operation: "get services" }.into()),
+        }
+    }
+
+    pub async fn read(&self, handle: u32) -> Result<Bytes, DeviceError> {
+        let req = api::BluetoothGattReadRequest { address: self.address, handle };
+        match self.proxy.request(self.address, GattOp::Read(handle), MessageType::BluetoothGATTReadRequest, req).await? {
+            GattReply::Data(data) => Ok(data),
+            _ => Err(GattError::UnexpectedReply { address: self.address, operation: "read" }.into()),
+        }
+    }
+
+    /// Write to a characteristic, waiting for the device to acknowledge it if `response`
+    pub async fn write(&self, handle: u32, data: &[u8], response: bool) -> Result<(), DeviceError> {
+        let req = api::BluetoothGattWriteRequest { address: self.address, handle, response, data: data.to_vec() };
+        if !response {
+            return self.proxy.handle.call(move |dev| Box::pin(async move {
+                dev.send(MessageType::BluetoothGATTWriteRequest, &req).await
+            })).await?;
+        }
+        self.proxy.request(self.address, GattOp::Write(handle), MessageType::BluetoothGATTWriteRequest, req).await?;
+        Ok(())
+    }
+
+    pub async fn read_descriptor(&self, handle: u32) -> Result<Bytes, DeviceError> {
+        let req = api::BluetoothGattReadDescriptorRequest { address: self.address, handle };
+        match self.proxy.request(self.address, GattOp::ReadDescriptor(handle), MessageType::BluetoothGATTReadDescriptorRequest, req).await? {
+            GattReply::Data(data) => Ok(data),
+            _ => Err(GattError::UnexpectedReply { address: self.address, operation: "read descriptor" }.into()),
+        }
+    }
+
+    pub async fn write_descriptor(&self, handle: u32, data: &[u8]) -> Result<(), DeviceError> {
+        let req = api::BluetoothGattWriteDescriptorRequest { address: self.address, handle, data: data.to_vec() };
+        self.proxy.request(self.address, GattOp::WriteDescriptor(handle), MessageType::BluetoothGATTWriteDescriptorRequest, req).await?;
+        Ok(())
+    }
+
+    /// Enable notifications for a characteristic and stream them (ends when the device disconnects).
+    /// Note: the client characteristic configuration descriptor (`0x2902`) may also have to be written.
+    pub async fn notify(&self, handle: u32, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<GattNotification>, DeviceError> {
+        let address = self.address;
+        // subscribe first, notifications can arrive before the notify response
+        let subscription = self.proxy.handle.call(move |dev| Box::pin(async move {
+            dev.gatt.notify_tx.entry(address).or_default()
+                .subscribe_filtered(buffer_size, policy, move |n| n.handle == handle)
+        })).await?;
+        let req = api::BluetoothGattNotifyRequest { address, handle, enable: true };
+        if let Err(e) = self.proxy.request(address, GattOp::Notify(handle), MessageType::BluetoothGATTNotifyRequest, req).await {
+            drop(subscription);
+            // the device task may be gone already
+            let _ = self.proxy.handle.call(move |dev| Box::pin(async move {
+                if let Some(tx) = dev.gatt.notify_tx.get_mut(&address) && !tx.has_subscribers() {
+                    dev.gatt.notify_tx.remove(&address);
+                }
+            })).await;
+            return Err(e);
+        }
+        Ok(subscription)
+    }
+
+    pub async fn stop_notify(&self, handle: u32) -> Result<(), DeviceError> {
+        let req = api::BluetoothGattNotifyRequest { address: self.address, handle, enable: false };
+        self.proxy.request(self.address, GattOp::Notify(handle), MessageType::BluetoothGATTNotifyRequest, req).await?;
+        Ok(())
+    }
+
+    pub async fn pair(&self) -> Result<(), DeviceError> {
+        let request_type = api::BluetoothDeviceRequestType::Pair;
+        self.proxy.device_request(self.address, GattOp::Pair, request_type, None).await?;
+        Ok(())
+    }
+
+    pub async fn unpair(&self) -> Result<(), DeviceError> {
+        let request_type = api::BluetoothDeviceRequestType::Unpair;
+        self.proxy.device_request(self.address, GattOp::Unpair, request_type, None).await?;
+        Ok(())
+    }
+
+    /// Clear the services the proxy cached for this device
+    pub async fn clear_cache(&self) -> Result<(), DeviceError> {
+        let request_type = api::BluetoothDeviceRequestType::ClearCache;
+        self.proxy.device_request(self.address, GattOp::ClearCache, request_type, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn uuid() {
+        let uuid = uuid_from_u64s(&[0x0000_2a19_0000_1000, 0x8000_0080_5f9b_34fb]);
+        assert_eq!(uuid, "00002a19-0000-1000-8000-00805f9b34fb");
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(GattStatus::from(0x05), GattStatus::InsufficientAuthentication);
+        assert_eq!(GattStatus::from(-1), GattStatus::NotConnected);
+        assert_eq!(GattStatus::from(0x1234).to_string(), "unknown error 0x1234");
+    }
+
+    #[tokio::test]
+    async fn bluetooth_gatt() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+        let proxy = handle.bluetooth_proxy();
+        let address = 0xA4C1_3811_2233;
+
+        let connecting = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { proxy.connect(address, Some(1)).await }
+        });
+        let req: api::BluetoothDeviceRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothDeviceRequest)).await.unwrap().unwrap();
+        assert_eq!((req.address, req.has_address_type, req.address_type), (address, true, 1));
+        mock.push(MessageType::BluetoothDeviceConnectionResponse, &api::BluetoothDeviceConnectionResponse { address, connected: true, mtu: 247, error: 0 });
+        let conn = timeout(TIMEOUT, connecting).await.unwrap().unwrap().unwrap();
+        assert_eq!(conn.mtu, 247);
+
+        //services arrive in several responses
+        let services = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.services().await }
+        });
+        let _: api::BluetoothGattGetServicesRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTGetServicesRequest)).await.unwrap().unwrap();
+        let battery = |handle| api::BluetoothGattService {
+            uuid: vec![0x0000_180f_0000_1000, 0x8000_0080_5f9b_34fb],
+            handle,
+            characteristics: vec![api::BluetoothGattCharacteristic {
+                uuid: vec![0x0000_2a19_0000_1000, 0x8000_0080_5f9b_34fb],
+                handle: handle + 1,
+                properties: 0x12,
+                descriptors: vec![],
+            }],
+        };
+        mock.push(MessageType::BluetoothGATTGetServicesResponse, &api::BluetoothGattGetServicesResponse { address, services: vec![battery(10)] });
+        mock.push(MessageType::BluetoothGATTGetServicesResponse, &api::BluetoothGattGetServicesResponse { address, services: vec![battery(20)] });
+        mock.push(MessageType::BluetoothGATTGetServicesDoneResponse, &api::BluetoothGattGetServicesDoneResponse { address });
+        let services = timeout(TIMEOUT, services).await.unwrap().unwrap().unwrap();
+        assert_eq!(services.len(), 2);
+        let level = services[1].characteristic("00002a19-0000-1000-8000-00805f9b34fb").unwrap();
+        assert_eq!(level.handle, 21);
+
+        //read, correlated by handle
+        let read = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.read(21).await }
+        });
+        let _: api::BluetoothGattReadRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTReadRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::BluetoothGATTReadResponse, &api::BluetoothGattReadResponse { address, handle: 99, data: vec![0] });
+        mock.push(MessageType::BluetoothGATTReadResponse, &api::BluetoothGattReadResponse { address, handle: 21, data: vec![87] });
+        assert_eq!(&timeout(TIMEOUT, read).await.unwrap().unwrap().unwrap()[..], [87]);
+
+        //errors are typed
+        let write = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.write(21, &[1], true).await }
+        });
+        let _: api::BluetoothGattWriteRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTWriteRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::BluetoothGATTErrorResponse, &api::BluetoothGattErrorResponse { address, handle: 21, error: 0x03 });
+        match timeout(TIMEOUT, write).await.unwrap().unwrap() {
+            Err(DeviceError::Gatt(GattError::Failed { handle: 21, status: GattStatus::WriteNotPermitted, .. })) => {}
+            res => panic!("unexpected {res:?}"),
+        }
+
+        //an error only fails the oldest request on the handle, descriptor writes are tracked separately
+        let write = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.write(21, &[1], true).await }
+        });
+        let _: api::BluetoothGattWriteRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTWriteRequest)).await.unwrap().unwrap();
+        let write_descriptor = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.write_descriptor(21, &[1, 0]).await }
+        });
+        let _: api::BluetoothGattWriteDescriptorRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTWriteDescriptorRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::BluetoothGATTErrorResponse, &api::BluetoothGattErrorResponse { address, handle: 21, error: 0x03 });
+        assert!(matches!(timeout(TIMEOUT, write).await.unwrap().unwrap(), Err(DeviceError::Gatt(GattError::Failed { handle: 21, .. }))));
+        assert!(!write_descriptor.is_finished());
+        mock.push(MessageType::BluetoothGATTWriteResponse, &api::BluetoothGattWriteResponse { address, handle: 21 });
+        timeout(TIMEOUT, write_descriptor).await.unwrap().unwrap().unwrap();
+
+        //a failed notify request doesn't leave a subscription behind
+        let notify = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.notify(21, 8, OverflowPolicy::DropOldest).await }
+        });
+        let _: api::BluetoothGattNotifyRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTNotifyRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::BluetoothGATTErrorResponse, &api::BluetoothGattErrorResponse { address, handle: 21, error: 0x05 });
+        assert!(matches!(timeout(TIMEOUT, notify).await.unwrap().unwrap(), Err(DeviceError::Gatt(GattError::Failed { status: GattStatus::InsufficientAuthentication, .. }))));
+        let subscribed = timeout(TIMEOUT, handle.call(move |dev| Box::pin(async move { dev.gatt.has_notify_subscription(address) }))).await.unwrap().unwrap();
+        assert!(!subscribed);
+
+        //notifications
+        let notify = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.notify(21, 8, OverflowPolicy::DropOldest).await }
+        });
+        let req: api::BluetoothGattNotifyRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTNotifyRequest)).await.unwrap().unwrap();
+        assert!(req.enable);
+        mock.push(MessageType::BluetoothGATTNotifyResponse, &api::BluetoothGattNotifyResponse { address, handle: 21 });
+        let mut notifications = timeout(TIMEOUT, notify).await.unwrap().unwrap().unwrap();
+        mock.push(MessageType::BluetoothGATTNotifyDataResponse, &api::BluetoothGattNotifyDataResponse { address, handle: 31, data: vec![1] });
+        mock.push(MessageType::BluetoothGATTNotifyDataResponse, &api::BluetoothGattNotifyDataResponse { address, handle: 21, data: vec![86] });
+        assert_eq!(&timeout(TIMEOUT, notifications.recv()).await.unwrap().unwrap().data[..], [86]);
+
+        //a disconnect fails pending requests and ends notifications
+        let read = tokio::spawn({
+            let conn = conn.clone();
+            async move { conn.read(21).await }
+        });
+        let _: api::BluetoothGattReadRequest = timeout(TIMEOUT, mock.wait_for(MessageType::BluetoothGATTReadRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::BluetoothDeviceConnectionResponse, &api::BluetoothDeviceConnectionResponse { address, connected: false, mtu: 0, error: 0 });
+        assert!(matches!(timeout(TIMEOUT, read).await.unwrap().unwrap(), Err(DeviceError::Gatt(GattError::Disconnected(a))) if a == address));
+        assert!(timeout(TIMEOUT, notifications.recv()).await.unwrap().is_none());
+        assert!(timeout(TIMEOUT, proxy.connections()).await.unwrap().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod advertisement;
+pub mod gatt;
+pub mod proxy;
+pub mod scheduler;
@@ -0,0 +1,117 @@
+use std::ops::BitOr;
+use tokio::sync::watch;
+use crate::{api, device::ESPHomeDevice, error::DeviceError, handle::DeviceHandle, model::MessageType};
+
+/// What a bluetooth proxy supports (`DeviceInfoResponse.bluetooth_proxy_feature_flags`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct BluetoothProxyFeatures(pub u32);
+
+impl BluetoothProxyFeatures {
+    pub const PASSIVE_SCAN: Self = Self(1 << 0);
+    pub const ACTIVE_CONNECTIONS: Self = Self(1 << 1);
+    pub const REMOTE_CACHING: Self = Self(1 << 2);
+    pub const PAIRING: Self = Self(1 << 3);
+    pub const CACHE_CLEARING: Self = Self(1 << 4);
+    pub const RAW_ADVERTISEMENTS: Self = Self(1 << 5);
+    pub const STATE_AND_MODE: Self = Self(1 << 6);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Features of the device, derived from `legacy_bluetooth_proxy_version` for older firmware
+    pub fn from_device_info(info: &api::DeviceInfoResponse) -> Self {
+        if info.bluetooth_proxy_feature_flags != 0 {
+            return Self(info.bluetooth_proxy_feature_flags);
+        }
+        let legacy = [
+            Self::PASSIVE_SCAN,
+            Self::ACTIVE_CONNECTIONS,
+            Self::REMOTE_CACHING,
+            Self::PAIRING | Self::CACHE_CLEARING,
+            Self::RAW_ADVERTISEMENTS,
+        ];
+        legacy.into_iter()
+            .take(info.legacy_bluetooth_proxy_version as usize)
+            .fold(Self::default(), |a, b| a | b)
+    }
+}
+
+impl BitOr for BluetoothProxyFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Active connection slots of a bluetooth proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BluetoothConnectionsFree {
+    pub free: u32,
+    pub limit: u32,
+    /// addresses currently holding a slot
+    pub allocated: Vec<u64>,
+}
+
+impl From<api::BluetoothConnectionsFreeResponse> for BluetoothConnectionsFree {
+    fn from(value: api::BluetoothConnectionsFreeResponse) -> Self {
+        Self { free: value.free, limit: value.limit, allocated: value.allocated }
+    }
+}
+
+impl ESPHomeDevice {
+    /// Bluetooth proxy features (fetched once per connection)
+    pub async fn bluetooth_proxy_features(&mut self) -> Result<BluetoothProxyFeatures, DeviceError> {
+        if let Some(features) = self.ble_proxy_features {
+            return Ok(features);
+        }
+        let features = BluetoothProxyFeatures::from_device_info(&self.device_info().await?);
+        self.ble_proxy_features = Some(features);
+        Ok(features)
+    }
+
+    /// Watch the free/allocated connection slots (the proxy sends them on every change)
+    pub async fn watch_bluetooth_connections_free(&mut self) -> Result<watch::Receiver<BluetoothConnectionsFree>, DeviceError> {
+        if !self.ble_slots_subscribed {
+            self.send(MessageType::SubscribeBluetoothConnectionsFreeRequest, &api::SubscribeBluetoothConnectionsFreeRequest {}).await?;
+            self.ble_slots_subscribed = true;
+        }
+        Ok(self.ble_slots_tx.subscribe())
+    }
+}
+
+impl DeviceHandle {
+    /// see `ESPHomeDevice::bluetooth_proxy_features`
+    pub async fn bluetooth_proxy_features(&self) -> Result<BluetoothProxyFeatures, DeviceError> {
+        self.call(|dev| Box::pin(dev.bluetooth_proxy_features())).await?
+    }
+
+    /// see `ESPHomeDevice::watch_bluetooth_connections_free`
+    pub async fn watch_bluetooth_connections_free(&self) -> Result<watch::Receiver<BluetoothConnectionsFree>, DeviceError> {
+        self.call(|dev| Box::pin(dev.watch_bluetooth_connections_free())).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features() {
+        let info = api::DeviceInfoResponse { bluetooth_proxy_feature_flags: 0b0100111, ..Default::default() };
+        let features = BluetoothProxyFeatures::from_device_info(&info);
+        assert!(features.contains(BluetoothProxyFeatures::ACTIVE_CONNECTIONS | BluetoothProxyFeatures::RAW_ADVERTISEMENTS));
+        assert!(!features.contains(BluetoothProxyFeatures::PAIRING));
+
+        let legacy = api::DeviceInfoResponse { legacy_bluetooth_proxy_version: 4, ..Default::default() };
+        let features = BluetoothProxyFeatures::from_device_info(&legacy);
+        assert!(features.contains(BluetoothProxyFeatures::CACHE_CLEARING | BluetoothProxyFeatures::REMOTE_CACHING));
+        assert!(!features.contains(BluetoothProxyFeatures::RAW_ADVERTISEMENTS));
+        assert!(BluetoothProxyFeatures::from_device_info(&api::DeviceInfoResponse::default()).is_empty());
+    }
+}
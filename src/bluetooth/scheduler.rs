@@ -0,0 +1,145 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+use crate::{
+    bluetooth::{gatt::{BluetoothProxy, GattConnection, GattError}, proxy::BluetoothConnectionsFree},
+    error::DeviceError, handle::DeviceHandle, subscription::OverflowPolicy
+};
+
+/// How long an advertisement counts towards picking a proxy
+pub const RSSI_MAX_AGE: Duration = Duration::from_secs(60);
+
+const ADVERTISEMENT_BUFFER_SIZE: usize = 256;
+
+struct ScheduledProxy {
+    proxy: BluetoothProxy,
+    slots: watch::Receiver<BluetoothConnectionsFree>,
+    task: JoinHandle<()>,
+}
+
+/// address -> proxy index -> (rssi, when)
+type RssiTable = HashMap<u64, HashMap<usize, (i32, Instant)>>;
+
+/// Picks which of several bluetooth proxies (spawned devices) should connect to a BLE device:
+/// one with a free connection slot that recently heard it with the best RSSI
+#[derive(Default)]
+pub struct BluetoothScheduler {
+    proxies: Vec<ScheduledProxy>,
+    rssi: Arc<Mutex<RssiTable>>,
+    /// advertisements older than this are ignored (default `RSSI_MAX_AGE`)
+    pub max_age: Option<Duration>,
+}
+
+impl BluetoothScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking advertisements and connection slots of the proxy
+    pub async fn add_proxy(&mut self, handle: DeviceHandle) -> Result<(), DeviceError> {
+        let slots = handle.watch_bluetooth_connections_free().await?;
+        let mut advs = handle.subscribe_bluetooth_advertisements(ADVERTISEMENT_BUFFER_SIZE, OverflowPolicy::DropOldest).await?;
+        let index = self.proxies.len();
+        let rssi = self.rssi.clone();
+        let task = tokio::spawn(async move {
+            while let Some(adv) = advs.recv().await {
+                rssi.lock().unwrap().entry(adv.address).or_default().insert(index, (adv.rssi, Instant::now()));
+            }
+        });
+        self.proxies.push(ScheduledProxy { proxy: handle.bluetooth_proxy(), slots, task });
+        Ok(())
+    }
+
+    /// Proxies that can connect to `address`, best first
+    pub fn candidates(&self, address: u64) -> Vec<BluetoothProxy> {
+        let max_age = self.max_age.unwrap_or(RSSI_MAX_AGE);
+        let rssi = self.rssi.lock().unwrap();
+        let Some(heard) = rssi.get(&address) else {
+            return Vec::new();
+        };
+        let mut candidates: Vec<_> = heard.iter()
+            .filter(|(_, (_, when))| when.elapsed() <= max_age)
+            .filter(|(index, _)| self.proxies[**index].slots.borrow().free > 0)
+            .map(|(index, (rssi, _))| (*rssi, *index))
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        candidates.into_iter().map(|(_, index)| self.proxies[index].proxy.clone()).collect()
+    }
+
+    /// The proxy with a free slot and the best recent RSSI for `address`
+    pub fn pick(&self, address: u64) -> Option<BluetoothProxy> {
+        self.candidates(address).into_iter().next()
+    }
+
+    /// Connect through the best proxy, falling back to the next candidate if connecting fails
+    pub async fn connect(&self, address: u64, address_type: Option<u32>) -> Result<GattConnection, DeviceError> {
+        let mut last_error = None;
+        for proxy in self.candidates(address) {
+            match proxy.connect(address, address_type).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or(GattError::NoProxyAvailable(address).into()))
+    }
+}
+
+impl Drop for BluetoothScheduler {
+    fn drop(&mut self) {
+        for proxy in &self.proxies {
+            proxy.task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::api;
+    use crate::bluetooth::proxy::BluetoothProxyFeatures;
+    use crate::device::ESPHomeDevice;
+    use crate::mock::MockDevice;
+    use crate::model::MessageType;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn bluetooth_scheduler() {
+        let features = api::DeviceInfoResponse { bluetooth_proxy_feature_flags: 0b100111, ..Default::default() };
+        let near = mock_builder().name("near").device_info(features.clone()).start().await.unwrap();
+        let far = mock_builder().name("far").device_info(features).start().await.unwrap();
+        let mut scheduler = BluetoothScheduler::new();
+        for mock in [&near, &far] {
+            let handle = ESPHomeDevice::new_plain(mock.addr(), String::new()).spawn();
+            timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+            assert!(handle.bluetooth_proxy_features().await.unwrap().contains(BluetoothProxyFeatures::REMOTE_CACHING));
+            timeout(TIMEOUT, scheduler.add_proxy(handle)).await.unwrap().unwrap();
+            let _: api::SubscribeBluetoothConnectionsFreeRequest = timeout(TIMEOUT, mock.wait_for(MessageType::SubscribeBluetoothConnectionsFreeRequest)).await.unwrap().unwrap();
+            mock.push(MessageType::BluetoothConnectionsFreeResponse, &api::BluetoothConnectionsFreeResponse { free: 3, limit: 3, allocated: vec![] });
+        }
+        let address = 0x1122_3344_5566;
+        assert!(scheduler.pick(address).is_none());
+
+        let heard = |mock: &MockDevice, rssi| mock.push(MessageType::BluetoothLERawAdvertisementsResponse, &api::BluetoothLeRawAdvertisementsResponse {
+            advertisements: vec![api::BluetoothLeRawAdvertisement { address, rssi, address_type: 0, data: vec![] }],
+        });
+        heard(&far, -90);
+        heard(&near, -50);
+        let candidates = async |scheduler: &BluetoothScheduler, n: usize| {
+            while scheduler.candidates(address).len() != n {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            scheduler.pick(address).unwrap().handle().device_info().await.unwrap().name
+        };
+        assert_eq!(timeout(TIMEOUT, candidates(&scheduler, 2)).await.unwrap(), "near");
+
+        //no free slots left on the closest proxy
+        near.push(MessageType::BluetoothConnectionsFreeResponse, &api::BluetoothConnectionsFreeResponse { free: 0, limit: 3, allocated: vec![1, 2, 3] });
+        assert_eq!(timeout(TIMEOUT, candidates(&scheduler, 1)).await.unwrap(), "far");
+
+        let connecting = tokio::spawn(async move { scheduler.connect(address, None).await });
+        let req: api::BluetoothDeviceRequest = timeout(TIMEOUT, far.wait_for(MessageType::BluetoothDeviceRequest)).await.unwrap().unwrap();
+        assert_eq!(req.request_type, i32::from(api::BluetoothDeviceRequestType::ConnectV3WithCache));
+        far.push(MessageType::BluetoothDeviceConnectionResponse, &api::BluetoothDeviceConnectionResponse { address, connected: true, mtu: 23, error: 0 });
+        assert_eq!(timeout(TIMEOUT, connecting).await.unwrap().unwrap().unwrap().mtu, 23);
+    }
+}
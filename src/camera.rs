@@ -0,0 +1,227 @@
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use crate::{
+    api, connection::options::{with_timeout, TimeoutStage}, device::ESPHomeDevice, entity::EntityType, error::DeviceError, handle::DeviceHandle, model::MessageType,
+    subscription::{OverflowPolicy, Subscription}
+};
+
+/// Interval Home Assistant uses to re-request a camera stream (the device stops streaming if not re-requested)
+pub const CAMERA_STREAM_REFRESH: Duration = Duration::from_secs(4);
+
+/// A complete image from a camera (reassembled from all chunks)
+#[derive(Debug, Clone)]
+pub struct CameraFrame {
+    pub key: u32,
+    /// JPEG
+    pub data: Bytes,
+}
+
+impl ESPHomeDevice {
+    fn camera_key(&self, object_id: &str) -> Result<u32, DeviceError> {
+        self.get_camera_key_from_name(object_id)
+            .ok_or_else(|| DeviceError::EntityNotFound(EntityType::Camera, object_id.to_string()))
+    }
+
+    /// subscribe to frames from the camera, then request an image or stream
+    /// (requests go to all cameras of the device)
+    pub(crate) async fn request_camera_frames(
+        &mut self,
+        object_id: &str,
+        req: api::CameraImageRequest,
+        buffer_size: usize,
+        policy: OverflowPolicy
+    ) -> Result<Subscription<CameraFrame>, DeviceError> {
+        let key = self.camera_key(object_id)?;
+        let frames = self.camera_tx.subscribe_filtered(buffer_size, policy, move |frame| frame.key == key);
+        self.send(MessageType::CameraImageRequest, &req).await?;
+        Ok(frames)
+    }
+
+    /// send `req`, then wait for the next complete image of the camera `key` (any camera if None)
+    pub(crate) async fn request_camera_image(&mut self, key: Option<u32>, req: &api::CameraImageRequest) -> Result<oneshot::Receiver<CameraFrame>, DeviceError> {
+        self.send(MessageType::CameraImageRequest, req).await?;
+        let (tx, rx) = oneshot::channel();
+        self.camera_waiters.push_back((key, tx));
+        Ok(rx)
+    }
+
+    async fn camera_image(&mut self, key: Option<u32>, req: &api::CameraImageRequest) -> Result<CameraFrame, DeviceError> {
+        with_timeout(self.request_timeout(), TimeoutStage::Request, async {
+            let rx = self.request_camera_image(key, req).await?;
+            self.read_until_reply(rx, true).await
+        }).await
+    }
+
+    /// Request a single image from the camera and wait for all of its chunks
+    pub async fn camera_snapshot(&mut self, object_id: &str) -> Result<CameraFrame, DeviceError> {
+        let key = self.camera_key(object_id)?;
+        self.camera_image(Some(key), &api::CameraImageRequest { single: true, stream: false }).await
+    }
+
+    /// Request an image (`req` goes to all cameras) and return the first complete one, see `camera_snapshot`
+    pub async fn get_camera_image(&mut self, req: &api::CameraImageRequest) -> Result<api::CameraImageResponse, DeviceError> {
+        let frame = self.camera_image(None, req).await?;
+        Ok(api::CameraImageResponse { key: frame.key, data: frame.data.to_vec(), done: true })
+    }
+
+    /// Request a stream of images from the camera.
+    /// A spawned device re-requests the stream every `refresh` (ex. `CAMERA_STREAM_REFRESH`) until all streams are dropped.
+    /// An unspawned device neither refreshes the stream nor reads frames outside of `process_incoming` and requests.
+    pub async fn camera_stream(
+        &mut self,
+        object_id: &str,
+        buffer_size: usize,
+        policy: OverflowPolicy,
+        refresh: Option<Duration>
+    ) -> Result<Subscription<CameraFrame>, DeviceError> {
+        let req = api::CameraImageRequest { single: false, stream: true };
+        let frames = self.request_camera_frames(object_id, req, buffer_size, policy).await?;
+        if refresh.is_some() {
+            self.camera_refresh = refresh;
+        }
+        Ok(frames)
+    }
+
+    /// re-request the stream, or stop refreshing once nobody is listening
+    pub(crate) async fn refresh_camera_stream(&mut self) -> Result<(), DeviceError> {
+        if !self.camera_tx.has_subscribers() {
+            self.camera_refresh = None;
+            return Ok(());
+        }
+        self.send(MessageType::CameraImageRequest, &api::CameraImageRequest { single: false, stream: true }).await
+    }
+
+    /// add a chunk, sending the frame once it's done
+    pub(crate) async fn on_camera_image(&mut self, res: api::CameraImageResponse) {
+        let buf = self.camera_buffers.entry(res.key).or_default();
+        buf.extend_from_slice(&res.data);
+        if res.done {
+            let data = self.camera_buffers.remove(&res.key).unwrap_or_default().freeze();
+            let frame = CameraFrame { key: res.key, data };
+            // one image answers every snapshot of that camera
+            for (key, tx) in std::mem::take(&mut self.camera_waiters) {
+                if tx.is_closed() {
+                    // gave up waiting (ex. timed out)
+                    continue;
+                }
+                if key.is_none_or(|key| key == res.key) {
+                    let _ = tx.send(frame.clone());
+                }
+                else {
+                    self.camera_waiters.push_back((key, tx));
+                }
+            }
+            self.camera_tx.send(frame).await;
+        }
+    }
+}
+
+impl DeviceHandle {
+    /// Request a single image from the camera and wait for all of its chunks
+    pub async fn camera_snapshot(&self, object_id: &str) -> Result<CameraFrame, DeviceError> {
+        let object_id = object_id.to_string();
+        let (rx, request_timeout) = self.call(move |dev| Box::pin(async move {
+            let key = dev.camera_key(&object_id)?;
+            let rx = dev.request_camera_image(Some(key), &api::CameraImageRequest { single: true, stream: false }).await?;
+            Ok::<_, DeviceError>((rx, dev.request_timeout()))
+        })).await??;
+        // dropped if the connection is lost
+        with_timeout(request_timeout, TimeoutStage::Request, async { rx.await.map_err(|_| DeviceError::NotConnected) }).await
+    }
+
+    /// see `ESPHomeDevice::camera_stream`
+    pub async fn camera_stream(
+        &self,
+        object_id: &str,
+        buffer_size: usize,
+        policy: OverflowPolicy,
+        refresh: Option<Duration>
+    ) -> Result<Subscription<CameraFrame>, DeviceError> {
+        let object_id = object_id.to_string();
+        self.call(move |dev| Box::pin(async move {
+            dev.camera_stream(&object_id, buffer_size, policy, refresh).await
+        })).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use tokio::time::timeout;
+    use crate::connection::options::ConnectionOptions;
+    use crate::mock::MockDevice;
+    use crate::test_util::*;
+
+    fn push_chunk(mock: &MockDevice, key: u32, data: &[u8], done: bool) {
+        mock.push(MessageType::CameraImageResponse, &api::CameraImageResponse { key, data: data.to_vec(), done });
+    }
+
+    #[tokio::test]
+    async fn camera_snapshot() {
+        let (mock, mut dev) = connected_plain(mock_builder()).await;
+
+        let (frame, _) = tokio::join!(timeout(TIMEOUT, dev.camera_snapshot("doorbell")), async {
+            let req: api::CameraImageRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CameraImageRequest)).await.unwrap().unwrap();
+            assert!(req.single && !req.stream);
+            // another camera's chunks are interleaved
+            push_chunk(&mock, 3, b"\xff\xd8first", false);
+            push_chunk(&mock, 9, b"other", true);
+            push_chunk(&mock, 3, b"second", false);
+            push_chunk(&mock, 3, b"third\xff\xd9", true);
+        });
+        assert_eq!(&frame.unwrap().unwrap().data[..], b"\xff\xd8firstsecondthird\xff\xd9");
+        assert!(matches!(dev.camera_snapshot("missing").await, Err(DeviceError::EntityNotFound(..))));
+
+        // the first complete image of any camera
+        let req = api::CameraImageRequest { single: true, stream: false };
+        let (res, _) = tokio::join!(timeout(TIMEOUT, dev.get_camera_image(&req)), async {
+            let _: api::CameraImageRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CameraImageRequest)).await.unwrap().unwrap();
+            push_chunk(&mock, 9, b"other", true);
+        });
+        let res = res.unwrap().unwrap();
+        assert_eq!((res.key, &res.data[..], res.done), (9, &b"other"[..], true));
+
+        // a spawned device times out, or fails once the connection is lost
+        let options = ConnectionOptions { request_timeout: Some(Duration::from_millis(300)), ..Default::default() };
+        let handle = dev.with_connection_options(options).spawn();
+        let res = timeout(TIMEOUT, handle.camera_snapshot("doorbell")).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Request })));
+        let (res, _) = tokio::join!(timeout(TIMEOUT, handle.camera_snapshot("doorbell")), async {
+            let _: api::CameraImageRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CameraImageRequest)).await.unwrap().unwrap();
+            mock.disconnect_clients();
+        });
+        assert!(matches!(res.unwrap(), Err(DeviceError::NotConnected)));
+    }
+
+    #[tokio::test]
+    async fn camera_stream() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let mut frames = timeout(TIMEOUT, handle.camera_stream("doorbell", 4, OverflowPolicy::DropOldest, Some(Duration::from_millis(50))))
+            .await.unwrap().unwrap();
+        let req: api::CameraImageRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CameraImageRequest)).await.unwrap().unwrap();
+        assert!(req.stream);
+        push_chunk(&mock, 3, b"a", false);
+        push_chunk(&mock, 3, b"b", true);
+        push_chunk(&mock, 3, b"c", true);
+        assert_eq!(&timeout(TIMEOUT, frames.recv()).await.unwrap().unwrap().data[..], b"ab");
+        assert_eq!(&timeout(TIMEOUT, frames.recv()).await.unwrap().unwrap().data[..], b"c");
+
+        //re-requested while streaming
+        let req: api::CameraImageRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CameraImageRequest)).await.unwrap().unwrap();
+        assert!(req.stream);
+
+        let snapshot = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.camera_snapshot("doorbell").await }
+        });
+        while !mock.received().iter().any(|(t, m)| *t == MessageType::CameraImageRequest
+            && api::CameraImageRequest::decode(m.clone()).unwrap().single) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        push_chunk(&mock, 3, b"d", true);
+        assert_eq!(&timeout(TIMEOUT, snapshot).await.unwrap().unwrap().unwrap().data[..], b"d");
+    }
+}
@@ -0,0 +1,336 @@
+use std::time::Duration;
+use paste::paste;
+use crate::{api, device::ESPHomeDevice, entity::EntityType, error::DeviceError, handle::DeviceHandle};
+
+/// Generates a command builder per entity type.
+/// Every listed field gets a setter which also sets `has_<field>`.
+macro_rules! gen_command_builders {
+    ($($name:ident { $($field:ident: $typ:ty),* $(,)? }),* $(,)?) => {
+        paste! {
+            $(
+                /// Builder for `api::[<$name CommandRequest>]`, get one with `dev.[<$name:snake>]("object_id")`
+                pub struct [<$name Command>]<T> {
+                    target: T,
+                    req: api::[<$name CommandRequest>],
+                }
+
+                impl<T> [<$name Command>]<T> {
+                    fn new(target: T, key: u32) -> Self {
+                        Self { target, req: api::[<$name CommandRequest>] { key, ..Default::default() } }
+                    }
+
+                    pub fn request(&self) -> &api::[<$name CommandRequest>] {
+                        &self.req
+                    }
+
+                    $(
+                        #[allow(clippy::useless_conversion)]
+                        pub fn $field(mut self, value: $typ) -> Self {
+                            self.req.$field = value.into();
+                            self.req.[<has_ $field>] = true;
+                            self
+                        }
+                    )*
+                }
+
+                impl<'a> [<$name Command>]<&'a mut ESPHomeDevice> {
+                    pub async fn send(self) -> Result<(), DeviceError> {
+                        self.target.[<$name:snake _command>](&self.req).await
+                    }
+                }
+
+                impl<'a> [<$name Command>]<&'a DeviceHandle> {
+                    pub async fn send(self) -> Result<(), DeviceError> {
+                        self.target.[<$name:snake _command>](&self.req).await
+                    }
+                }
+
+                impl ESPHomeDevice {
+                    /// command builder for the entity with entity.object_id
+                    pub fn [<$name:snake>](&mut self, object_id: &str) -> Result<[<$name Command>]<&mut Self>, DeviceError> {
+                        let key = self.[<get_ $name:snake _key_from_name>](object_id)
+                            .ok_or_else(|| DeviceError::EntityNotFound(EntityType::$name, object_id.to_string()))?;
+                        Ok([<$name Command>]::new(self, key))
+                    }
+                }
+
+                impl DeviceHandle {
+                    /// command builder for the entity with entity.object_id
+                    pub async fn [<$name:snake>](&self, object_id: &str) -> Result<[<$name Command>]<&Self>, DeviceError> {
+                        let id = object_id.to_string();
+                        let key = self.call(move |dev| Box::pin(async move { dev.[<get_ $name:snake _key_from_name>](&id) })).await?
+                            .ok_or_else(|| DeviceError::EntityNotFound(EntityType::$name, object_id.to_string()))?;
+                        Ok([<$name Command>]::new(self, key))
+                    }
+                }
+            )*
+        }
+    }
+}
+
+gen_command_builders!(
+    Light {
+        state: bool,
+        brightness: f32,
+        color_mode: i32,
+        color_brightness: f32,
+        white: f32,
+        color_temperature: f32,
+        cold_white: f32,
+        warm_white: f32,
+        transition_length: u32,
+        flash_length: u32,
+        effect: impl Into<String>,
+    },
+    Cover {
+        position: f32,
+        tilt: f32,
+    },
+    Fan {
+        state: bool,
+        oscillating: bool,
+        direction: api::FanDirection,
+        speed_level: i32,
+        preset_mode: impl Into<String>,
+    },
+    Climate {
+        mode: api::ClimateMode,
+        target_temperature: f32,
+        target_temperature_low: f32,
+        target_temperature_high: f32,
+        fan_mode: api::ClimateFanMode,
+        swing_mode: api::ClimateSwingMode,
+        custom_fan_mode: impl Into<String>,
+        preset: api::ClimatePreset,
+        custom_preset: impl Into<String>,
+        target_humidity: f32,
+    },
+    Lock {
+        code: impl Into<String>,
+    },
+    Valve {
+        position: f32,
+    },
+    MediaPlayer {
+        command: api::MediaPlayerCommand,
+        volume: f32,
+        media_url: impl Into<String>,
+        announcement: bool,
+    },
+    Siren {
+        state: bool,
+        tone: impl Into<String>,
+        volume: f32,
+    },
+    AlarmControlPanel {},
+);
+
+impl<T> LightCommand<T> {
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+
+    /// each channel 0-1
+    pub fn rgb(mut self, red: f32, green: f32, blue: f32) -> Self {
+        self.req.has_rgb = true;
+        (self.req.red, self.req.green, self.req.blue) = (red, green, blue);
+        self
+    }
+
+    /// sets color_temperature (which is in mireds), 0 K is treated as 1 K
+    pub fn color_temp_kelvin(self, kelvin: u32) -> Self {
+        self.color_temperature(1_000_000.0 / kelvin.max(1) as f32)
+    }
+
+    /// in milliseconds, saturating at `u32::MAX`
+    pub fn transition(self, duration: Duration) -> Self {
+        self.transition_length(saturating_millis(duration))
+    }
+
+    /// in milliseconds, saturating at `u32::MAX`
+    pub fn flash(self, duration: Duration) -> Self {
+        self.flash_length(saturating_millis(duration))
+    }
+}
+
+fn saturating_millis(duration: Duration) -> u32 {
+    u32::try_from(duration.as_millis()).unwrap_or(u32::MAX)
+}
+
+impl<T> CoverCommand<T> {
+    pub fn open(self) -> Self {
+        self.position(1.0)
+    }
+
+    pub fn close(self) -> Self {
+        self.position(0.0)
+    }
+
+    pub fn stop(mut self) -> Self {
+        self.req.stop = true;
+        self
+    }
+}
+
+impl<T> FanCommand<T> {
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+}
+
+impl<T> LockCommand<T> {
+    pub fn command(mut self, command: api::LockCommand) -> Self {
+        self.req.command = command.into();
+        self
+    }
+
+    pub fn lock(self) -> Self {
+        self.command(api::LockCommand::LockLock)
+    }
+
+    pub fn unlock(self) -> Self {
+        self.command(api::LockCommand::LockUnlock)
+    }
+
+    pub fn open(self) -> Self {
+        self.command(api::LockCommand::LockOpen)
+    }
+}
+
+impl<T> ValveCommand<T> {
+    pub fn open(self) -> Self {
+        self.position(1.0)
+    }
+
+    pub fn close(self) -> Self {
+        self.position(0.0)
+    }
+
+    pub fn stop(mut self) -> Self {
+        self.req.stop = true;
+        self
+    }
+}
+
+impl<T> MediaPlayerCommand<T> {
+    pub fn play(self) -> Self {
+        self.command(api::MediaPlayerCommand::Play)
+    }
+
+    pub fn pause(self) -> Self {
+        self.command(api::MediaPlayerCommand::Pause)
+    }
+
+    pub fn play_media(self, url: impl Into<String>) -> Self {
+        self.media_url(url)
+    }
+}
+
+impl<T> SirenCommand<T> {
+    pub fn turn_on(self) -> Self {
+        self.state(true)
+    }
+
+    pub fn turn_off(self) -> Self {
+        self.state(false)
+    }
+
+    /// how long to sound for (whole seconds)
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.req.has_duration = true;
+        self.req.duration = u32::try_from(duration.as_secs()).unwrap_or(u32::MAX);
+        self
+    }
+}
+
+impl<T> AlarmControlPanelCommand<T> {
+    pub fn command(mut self, command: api::AlarmControlPanelStateCommand) -> Self {
+        self.req.command = command.into();
+        self
+    }
+
+    pub fn code(mut self, code: impl Into<String>) -> Self {
+        self.req.code = code.into();
+        self
+    }
+
+    pub fn disarm(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelDisarm)
+    }
+
+    pub fn arm_away(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelArmAway)
+    }
+
+    pub fn arm_home(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelArmHome)
+    }
+
+    pub fn arm_night(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelArmNight)
+    }
+
+    pub fn arm_vacation(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelArmVacation)
+    }
+
+    pub fn arm_custom_bypass(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelArmCustomBypass)
+    }
+
+    pub fn trigger(self) -> Self {
+        self.command(api::AlarmControlPanelStateCommand::AlarmControlPanelTrigger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::model::MessageType;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn command_builders() {
+        let (mock, mut dev) = connected_plain(mock_builder()
+            .entity(MessageType::ListEntitiesSirenResponse, &api::ListEntitiesSirenResponse {
+                object_id: "siren".to_string(),
+                key: 4,
+                ..Default::default()
+            })
+        ).await;
+
+        dev.light("rgbct_bulb").unwrap()
+            .turn_on()
+            .brightness(0.5)
+            .color_temp_kelvin(2500)
+            .transition(Duration::from_secs(2))
+            .send().await.unwrap();
+        let cmd: api::LightCommandRequest = timeout(TIMEOUT, mock.wait_for(MessageType::LightCommandRequest)).await.unwrap().unwrap();
+        assert_eq!(cmd.key, 1);
+        assert!(cmd.has_state && cmd.state && cmd.has_brightness && cmd.has_color_temperature && cmd.has_transition_length);
+        assert_eq!((cmd.brightness, cmd.color_temperature, cmd.transition_length), (0.5, 400.0, 2000));
+        assert!(!cmd.has_rgb && !cmd.has_effect);
+
+        let edge = dev.light("rgbct_bulb").unwrap().color_temp_kelvin(0).flash(Duration::MAX);
+        assert_eq!((edge.request().color_temperature, edge.request().flash_length), (1_000_000.0, u32::MAX));
+        assert_eq!(dev.siren("siren").unwrap().duration(Duration::MAX).request().duration, u32::MAX);
+
+        assert!(matches!(dev.light("missing"), Err(DeviceError::EntityNotFound(_, ref name)) if name == "missing"));
+        assert!(matches!(dev.fan("relay"), Err(DeviceError::EntityNotFound(..))));
+
+        let handle = dev.spawn();
+        handle.light("rgbct_bulb").await.unwrap().rgb(1.0, 0.0, 0.0).send().await.unwrap();
+        let cmd: api::LightCommandRequest = timeout(TIMEOUT, mock.wait_for(MessageType::LightCommandRequest)).await.unwrap().unwrap();
+        assert!(cmd.has_rgb && !cmd.has_state);
+        assert_eq!((cmd.red, cmd.green, cmd.blue), (1.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{api, model::ApiVersion};
+
+/// Cover position/stop replaced `legacy_command` and `legacy_state`
+pub const COVER_POSITION_API_VERSION: ApiVersion = ApiVersion::new(1, 1);
+/// User service ints are read from `int_` instead of the unsigned `legacy_int`
+pub const SIGNED_INT_API_VERSION: ApiVersion = ApiVersion::new(1, 3);
+/// Fan `speed_level` replaced the low/medium/high `speed`
+pub const FAN_SPEED_LEVEL_API_VERSION: ApiVersion = ApiVersion::new(1, 4);
+
+/// Translate between the current fields of a message and the ones an older device uses
+pub trait ApiCompat {
+    /// before sending a command, or after receiving a state
+    fn adapt(&mut self, _version: ApiVersion) {}
+}
+
+impl ApiCompat for api::CoverCommandRequest {
+    fn adapt(&mut self, version: ApiVersion) {
+        if version >= COVER_POSITION_API_VERSION {
+            return;
+        }
+        let command = match (self.stop, self.has_position) {
+            (true, _) => api::LegacyCoverCommand::Stop,
+            (false, true) if self.position >= 1.0 => api::LegacyCoverCommand::Open,
+            (false, true) if self.position <= 0.0 => api::LegacyCoverCommand::Close,
+            _ => return,
+        };
+        self.has_legacy_command = true;
+        self.legacy_command = command.into();
+    }
+}
+
+impl ApiCompat for api::CoverStateResponse {
+    fn adapt(&mut self, version: ApiVersion) {
+        if version < COVER_POSITION_API_VERSION {
+            self.position = match self.legacy_state() {
+                api::LegacyCoverState::Open => 1.0,
+                api::LegacyCoverState::Closed => 0.0,
+            };
+        }
+    }
+}
+
+// the deprecated fields are exactly what older devices need
+#[allow(deprecated)]
+impl ApiCompat for api::FanCommandRequest {
+    fn adapt(&mut self, version: ApiVersion) {
+        if version < FAN_SPEED_LEVEL_API_VERSION && self.has_speed_level {
+            self.has_speed = true;
+            self.speed = self.speed_level.clamp(1, 3) - 1;
+            self.has_speed_level = false;
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl ApiCompat for api::FanStateResponse {
+    fn adapt(&mut self, version: ApiVersion) {
+        if version < FAN_SPEED_LEVEL_API_VERSION {
+            self.speed_level = self.speed + 1;
+        }
+    }
+}
+
+macro_rules! no_compat {
+    ($($msg:ident),*) => { paste::paste! {
+        $(impl ApiCompat for api::$msg {})*
+    }}
+}
+
+no_compat! {
+    LightCommandRequest, SwitchCommandRequest, ClimateCommandRequest, NumberCommandRequest,
+    SelectCommandRequest, SirenCommandRequest, LockCommandRequest, ButtonCommandRequest,
+    MediaPlayerCommandRequest, AlarmControlPanelCommandRequest, TextCommandRequest,
+    DateCommandRequest, TimeCommandRequest, DateTimeCommandRequest, ValveCommandRequest,
+    UpdateCommandRequest,
+    BinarySensorStateResponse, LightStateResponse, SensorStateResponse, SwitchStateResponse,
+    TextSensorStateResponse, ClimateStateResponse, NumberStateResponse, SelectStateResponse,
+    SirenStateResponse, LockStateResponse, MediaPlayerStateResponse, AlarmControlPanelStateResponse,
+    TextStateResponse, DateStateResponse, TimeStateResponse, ValveStateResponse,
+    DateTimeStateResponse, UpdateStateResponse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::timeout;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::model::{API_VERSION, MessageType};
+    use crate::test_util::*;
+
+    #[test]
+    fn cover() {
+        let mut req = api::CoverCommandRequest { key: 1, has_position: true, position: 1.0, ..Default::default() };
+        req.adapt(ApiVersion::new(1, 10));
+        assert!(!req.has_legacy_command);
+        req.adapt(ApiVersion::new(1, 0));
+        assert!(req.has_legacy_command);
+        assert_eq!(req.legacy_command(), api::LegacyCoverCommand::Open);
+
+        let mut state = api::CoverStateResponse { legacy_state: api::LegacyCoverState::Closed.into(), position: 0.5, ..Default::default() };
+        state.adapt(ApiVersion::new(1, 0));
+        assert_eq!(state.position, 0.0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn fan() {
+        let mut req = api::FanCommandRequest { has_speed_level: true, speed_level: 3, ..Default::default() };
+        req.adapt(ApiVersion::new(1, 3));
+        assert!(req.has_speed && !req.has_speed_level);
+        assert_eq!(req.speed(), api::FanSpeed::High);
+
+        let mut state = api::FanStateResponse { speed: api::FanSpeed::Medium.into(), ..Default::default() };
+        state.adapt(ApiVersion::new(1, 3));
+        assert_eq!(state.speed_level, 2);
+    }
+
+    #[tokio::test]
+    async fn api_version() {
+        let (_mock, dev) = connected_plain(mock_builder().api_version(1, 10)).await;
+        assert_eq!(dev.api_version, Some(ApiVersion::new(1, 10)));
+        assert_eq!(dev.server_name, "mock_bulb");
+
+        //older minor: warn, then translate version dependent fields
+        let mock = mock_builder()
+            .api_version(1, 0)
+            .entity(MessageType::ListEntitiesCoverResponse, &api::ListEntitiesCoverResponse {
+                object_id: "garage".to_string(),
+                key: 4,
+                ..Default::default()
+            })
+            .start().await.unwrap();
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let mut dev = ESPHomeDevice::new_plain(mock.addr(), String::new())
+            .with_api_version_warning({
+                let warnings = warnings.clone();
+                move |client, device| warnings.lock().unwrap().push((client, device))
+            });
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(*warnings.lock().unwrap(), [(API_VERSION, ApiVersion::new(1, 0))]);
+        timeout(TIMEOUT, dev.cover("garage").unwrap().open().send()).await.unwrap().unwrap();
+        let req: api::CoverCommandRequest = timeout(TIMEOUT, mock.wait_for(MessageType::CoverCommandRequest)).await.unwrap().unwrap();
+        assert!(req.has_legacy_command);
+        assert_eq!(req.legacy_command(), api::LegacyCoverCommand::Open);
+
+        let mock = mock_builder().api_version(2, 0).start().await.unwrap();
+        let mut dev = ESPHomeDevice::new_plain(mock.addr(), String::new());
+        match timeout(TIMEOUT, dev.connect()).await.unwrap() {
+            Err(DeviceError::ApiVersionMismatch { device, .. }) => assert_eq!(device, ApiVersion::new(2, 0)),
+            res => panic!("unexpected {res:?}"),
+        }
+        assert!(!dev.connected);
+    }
+}
@@ -0,0 +1,149 @@
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::hash::{Hash, Hasher};
+use crate::{error::ConnectionError, model::MessageType};
+use super::{base::{AnyConnection, Connection}, noise::{NoiseConnection, NOISE_HELLO}, options::{with_timeout, ConnectionOptions, TimeoutStage}, plain::PlainConnection, transport::Transport};
+
+/// Framing a device uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encryption {
+    Plaintext,
+    Noise,
+}
+
+/// Open a stream, send a noise client hello and look at the preamble of the answer.
+/// ESPHome answers with its own preamble either way (a plaintext device sends `0x00` "Bad indicator byte").
+pub async fn detect_encryption(transport: &mut Transport, options: &ConnectionOptions) -> Result<Encryption, ConnectionError> {
+    let mut stream = with_timeout(options.connect_timeout, TimeoutStage::Connect, transport.open()).await?;
+    let probe = async {
+        stream.write_all(NOISE_HELLO).await?;
+        let mut preamble = [0u8; 1];
+        let len = stream.read(&mut preamble).await?;
+        Ok::<_, ConnectionError>((len, preamble))
+    };
+    let (len, preamble) = with_timeout(options.handshake_timeout, TimeoutStage::Handshake, probe).await?;
+    let _ = stream.shutdown().await;
+    match (len, preamble[0]) {
+        (0, _) => Err(ConnectionError::ConnectionClosed),
+        (_, 0x00) => Ok(Encryption::Plaintext),
+        (_, 0x01) => Ok(Encryption::Noise),
+        (_, preamble) => Err(ConnectionError::FrameHadWrongPreamble(preamble)),
+    }
+}
+
+/// Probes whether the device is encrypted before the first connect and uses the matching framing.
+/// Fails with `EncryptionRequired` if it is but no key was given, and `NotEncrypted` if a key was given for a plaintext device.
+/// Probing opens an extra stream, so the transport can't be `Transport::Stream`.
+pub struct AutoConnection {
+    conn: Box<AnyConnection>,
+    has_noise_psk: bool,
+    encryption: Option<Encryption>,
+}
+
+impl Hash for AutoConnection {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.conn.hash(state);
+    }
+}
+
+impl Connection for AutoConnection {
+    async fn send_message(&mut self, msg_type: MessageType, msg_bytes: &BytesMut) -> Result<(), ConnectionError> {
+        self.conn.send_message(msg_type, msg_bytes).await
+    }
+
+    async fn receive_message(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
+        self.conn.receive_message().await
+    }
+
+    async fn connect(&mut self) -> Result<(), ConnectionError> {
+        if self.encryption.is_none() {
+            let options = *self.conn.options();
+            let encryption = detect_encryption(self.conn.transport_mut(), &options).await?;
+            match (encryption, self.has_noise_psk) {
+                (Encryption::Noise, false) => return Err(ConnectionError::EncryptionRequired),
+                (Encryption::Plaintext, true) => return Err(ConnectionError::NotEncrypted),
+                _ => self.encryption = Some(encryption),
+            }
+        }
+        self.conn.connect().await
+    }
+
+    async fn disconnect(&mut self) -> Result<(), ConnectionError> {
+        self.conn.disconnect().await
+    }
+
+    fn options(&self) -> &ConnectionOptions {
+        self.conn.options()
+    }
+}
+
+impl AutoConnection {
+    /// Connect over TCP to `ip` (`host:port`), with noise if `noise_psk` is given
+    pub fn new(ip: String, noise_psk: Option<String>) -> Self {
+        Self::with_transport(Transport::Tcp(ip), noise_psk)
+    }
+
+    pub fn with_transport(transport: Transport, noise_psk: Option<String>) -> Self {
+        let has_noise_psk = noise_psk.is_some();
+        let conn = match noise_psk {
+            Some(noise_psk) => NoiseConnection::with_transport(transport, noise_psk).into(),
+            None => PlainConnection::with_transport(transport).into(),
+        };
+        Self { conn: Box::new(conn), has_noise_psk, encryption: None }
+    }
+
+    pub fn with_options(mut self, options: ConnectionOptions) -> Self {
+        *self.conn.options_mut() = options;
+        self
+    }
+
+    /// What the device uses (after the first connect)
+    pub fn encryption(&self) -> Option<Encryption> {
+        self.encryption
+    }
+
+    pub fn inner(&self) -> &AnyConnection {
+        &self.conn
+    }
+
+    pub fn inner_mut(&mut self) -> &mut AnyConnection {
+        &mut self.conn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn detect_encryption() {
+        let noise_mock = mock_builder().noise_psk(NOISE_PSK).start().await.unwrap();
+        let plain_mock = mock_builder().start().await.unwrap();
+
+        let mut dev = ESPHomeDevice::new_auto(noise_mock.addr(), Some(NOISE_PSK.to_string()));
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(dev.active_noise_psk().as_deref(), Some(NOISE_PSK));
+        let mut dev = ESPHomeDevice::new_auto(plain_mock.addr(), None);
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(dev.entities.light.len(), 1);
+
+        let mut dev = ESPHomeDevice::new_auto(noise_mock.addr(), None);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::EncryptionRequired))));
+        let mut dev = ESPHomeDevice::new_auto(plain_mock.addr(), Some(NOISE_PSK.to_string()));
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::NotEncrypted))));
+
+        //the explicit connection types report the same errors
+        let mut dev = ESPHomeDevice::new_plain(noise_mock.addr(), String::new());
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::EncryptionRequired))));
+        let mut dev = ESPHomeDevice::new_noise(plain_mock.addr(), NOISE_PSK.to_string());
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::NotEncrypted))));
+    }
+}
@@ -1,21 +1,73 @@
 use bytes::BytesMut;
+use futures::FutureExt;
 use crate::{error::ConnectionError, model::MessageType};
 
-use super::{noise::NoiseConnection, plain::PlainConnection};
+use super::{auto::AutoConnection, noise::NoiseConnection, options::{with_timeout, ConnectionOptions, TimeoutStage}, plain::PlainConnection, transport::Transport};
 
 #[allow(async_fn_in_trait)]
 pub trait Connection {
     async fn send_message(&mut self, msg_type: MessageType, msg_bytes: &BytesMut) -> Result<(), ConnectionError>;
-    async fn receive_message(&mut self, first_byte: Option<u8>) -> Result<(MessageType, BytesMut), ConnectionError>;
-    fn try_read_byte(&mut self) -> Result<Option<u8>, ConnectionError>;
+    /// Read the next message, waiting until a whole frame is received (cancel safe)
+    async fn receive_message(&mut self) -> Result<(MessageType, BytesMut), ConnectionError>;
+    /// Read the next message if a whole frame is already available, without waiting
+    fn try_receive_message(&mut self) -> Result<Option<(MessageType, BytesMut)>, ConnectionError> {
+        self.receive_message().now_or_never().transpose()
+    }
+    /// `receive_message`, failing if nothing arrives within the read timeout
+    async fn receive_message_timeout(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
+        let read_timeout = self.options().read_timeout;
+        with_timeout(read_timeout, TimeoutStage::Read, self.receive_message()).await
+    }
     async fn connect(&mut self) -> Result<(), ConnectionError>;
     async fn disconnect(&mut self) -> Result<(), ConnectionError>;
+    fn options(&self) -> &ConnectionOptions;
 }
 
 #[derive(Hash)]
 pub enum AnyConnection {
     Noise(NoiseConnection),
-    Plain(PlainConnection)
+    Plain(PlainConnection),
+    Auto(AutoConnection),
+}
+
+impl AnyConnection {
+    /// The noise connection (also when picked by an `AutoConnection`)
+    pub fn noise(&self) -> Option<&NoiseConnection> {
+        match self {
+            AnyConnection::Noise(con) => Some(con),
+            AnyConnection::Plain(_) => None,
+            AnyConnection::Auto(con) => con.inner().noise(),
+        }
+    }
+
+    pub fn noise_mut(&mut self) -> Option<&mut NoiseConnection> {
+        match self {
+            AnyConnection::Noise(con) => Some(con),
+            AnyConnection::Plain(_) => None,
+            AnyConnection::Auto(con) => con.inner_mut().noise_mut(),
+        }
+    }
+
+    /// MAC address the device must report in `DeviceInfoResponse`
+    pub fn expected_mac(&self) -> Option<&str> {
+        self.noise().and_then(|con| con.expected_mac.as_deref())
+    }
+
+    pub fn options_mut(&mut self) -> &mut ConnectionOptions {
+        match self {
+            AnyConnection::Noise(con) => &mut con.options,
+            AnyConnection::Plain(con) => &mut con.options,
+            AnyConnection::Auto(con) => con.inner_mut().options_mut(),
+        }
+    }
+
+    pub(crate) fn transport_mut(&mut self) -> &mut Transport {
+        match self {
+            AnyConnection::Noise(con) => &mut con.transport,
+            AnyConnection::Plain(con) => &mut con.transport,
+            AnyConnection::Auto(con) => con.inner_mut().transport_mut(),
+        }
+    }
 }
 
 impl From<NoiseConnection> for AnyConnection {
@@ -30,39 +82,50 @@ impl From<PlainConnection> for AnyConnection {
     }
 }
 
+impl From<AutoConnection> for AnyConnection {
+    fn from(value: AutoConnection) -> Self {
+        Self::Auto(value)
+    }
+}
+
 impl Connection for AnyConnection {
     async fn send_message(&mut self, msg_type: MessageType, msg_bytes: &BytesMut) -> Result<(), ConnectionError> {
         match self {
             AnyConnection::Noise(con) => con.send_message(msg_type, msg_bytes).await,
-            AnyConnection::Plain(con) => con.send_message(msg_type, msg_bytes).await
+            AnyConnection::Plain(con) => con.send_message(msg_type, msg_bytes).await,
+            AnyConnection::Auto(con) => Box::pin(con.send_message(msg_type, msg_bytes)).await,
         }
     }
 
-    async fn receive_message(&mut self, first_byte: Option<u8>) -> Result<(MessageType, BytesMut), ConnectionError> {
+    async fn receive_message(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
         match self {
-            AnyConnection::Noise(con) => con.receive_message(first_byte).await,
-            AnyConnection::Plain(con) => con.receive_message(first_byte).await
-        }
-    }
-
-    fn try_read_byte(&mut self) -> Result<Option<u8>, ConnectionError> {
-        match self {
-            AnyConnection::Noise(con) => con.try_read_byte(),
-            AnyConnection::Plain(con) => con.try_read_byte()
+            AnyConnection::Noise(con) => con.receive_message().await,
+            AnyConnection::Plain(con) => con.receive_message().await,
+            AnyConnection::Auto(con) => Box::pin(con.receive_message()).await,
         }
     }
 
     async fn connect(&mut self) -> Result<(), ConnectionError> {
         match self {
             AnyConnection::Noise(con) => con.connect().await,
-            AnyConnection::Plain(con) => con.connect().await
+            AnyConnection::Plain(con) => con.connect().await,
+            AnyConnection::Auto(con) => Box::pin(con.connect()).await,
         }
     }
 
     async fn disconnect(&mut self) -> Result<(), ConnectionError> {
         match self {
             AnyConnection::Noise(con) => con.disconnect().await,
-            AnyConnection::Plain(con) => con.disconnect().await
+            AnyConnection::Plain(con) => con.disconnect().await,
+            AnyConnection::Auto(con) => Box::pin(con.disconnect()).await,
+        }
+    }
+
+    fn options(&self) -> &ConnectionOptions {
+        match self {
+            AnyConnection::Noise(con) => con.options(),
+            AnyConnection::Plain(con) => con.options(),
+            AnyConnection::Auto(con) => con.options(),
         }
     }
 }
@@ -0,0 +1,191 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use crate::error::ConnectionError;
+use super::util::{decode_varu32, varu32_to_bytes};
+
+/// max size of a noise frame (length is a u16)
+pub const NOISE_MAX_FRAME_LEN: usize = 65535;
+
+/// Outer framing of the noise protocol: `0x01, len (u16 BE), frame`.
+/// Decodes into the frame (without header), buffering until the whole frame is read.
+#[derive(Debug, Default, Clone)]
+pub struct NoiseFrameCodec;
+
+impl Decoder for NoiseFrameCodec {
+    type Item = BytesMut;
+    type Error = ConnectionError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if src[0] != 0x01 {
+            return Err(ConnectionError::FrameHadWrongPreamble(src[0]));
+        }
+        if src.len() < 3 {
+            return Ok(None);
+        }
+        let frame_len = u16::from_be_bytes([src[1], src[2]]) as usize;
+        if src.len() < 3 + frame_len {
+            src.reserve(3 + frame_len - src.len());
+            return Ok(None);
+        }
+        src.advance(3);
+        Ok(Some(src.split_to(frame_len)))
+    }
+}
+
+impl Encoder<&[u8]> for NoiseFrameCodec {
+    type Error = ConnectionError;
+
+    fn encode(&mut self, frame: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if frame.len() > NOISE_MAX_FRAME_LEN {
+            return Err(ConnectionError::FrameTooLarge(frame.len()));
+        }
+        dst.reserve(3 + frame.len());
+        dst.put_u8(0x01);
+        dst.put_u16(frame.len() as u16);
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// Plaintext framing: `0x00, len (varint), type (varint), message`.
+/// Decodes into the message type number and message, buffering until the whole message is read.
+/// Unknown message types are left to the connection, so they don't end the stream.
+#[derive(Debug, Default, Clone)]
+pub struct PlainFrameCodec;
+
+impl Decoder for PlainFrameCodec {
+    type Item = (u16, BytesMut);
+    type Error = ConnectionError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if src[0] != 0x00 {
+            return Err(ConnectionError::FrameHadWrongPreamble(src[0]));
+        }
+        let Some((msg_len, len_size)) = decode_varu32(&src[1..])? else {
+            return Ok(None)
+        };
+        let Some((msg_type_num, type_size)) = decode_varu32(&src[1 + len_size..])? else {
+            return Ok(None)
+        };
+        let msg_type = u16::try_from(msg_type_num).map_err(|_| ConnectionError::MessageTypeTooLarge(msg_type_num))?;
+        let header_len = 1 + len_size + type_size;
+        let msg_len = msg_len as usize;
+        if src.len() < header_len + msg_len {
+            src.reserve(header_len + msg_len - src.len());
+            return Ok(None);
+        }
+        src.advance(header_len);
+        let msg = src.split_to(msg_len);
+        Ok(Some((msg_type, msg)))
+    }
+}
+
+impl Encoder<(u16, &[u8])> for PlainFrameCodec {
+    type Error = ConnectionError;
+
+    fn encode(&mut self, (msg_type, msg): (u16, &[u8]), dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let msg_len_var = varu32_to_bytes(msg.len() as u32);
+        let msg_type_var = varu32_to_bytes(msg_type as u32);
+        dst.reserve(1 + msg_len_var.len() + msg_type_var.len() + msg.len());
+        dst.put_u8(0x00);
+        dst.extend_from_slice(&msg_len_var);
+        dst.extend_from_slice(&msg_type_var);
+        dst.extend_from_slice(msg);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::MessageType;
+    use super::*;
+
+    fn noise_frames() -> (BytesMut, Vec<Vec<u8>>) {
+        let frames = vec![vec![0xAB; 300], vec![], vec![1, 2, 3]];
+        let mut bytes = BytesMut::new();
+        for frame in &frames {
+            NoiseFrameCodec.encode(frame.as_slice(), &mut bytes).unwrap();
+        }
+        (bytes, frames)
+    }
+
+    fn plain_messages() -> (BytesMut, Vec<(u16, Vec<u8>)>) {
+        let msgs = vec![
+            (MessageType::ListEntitiesDoneResponse as u16, vec![]),
+            (MessageType::LightStateResponse as u16, vec![0x55; 200]),
+            (200, vec![1, 2]),
+            (MessageType::PingRequest as u16, vec![]),
+        ];
+        let mut bytes = BytesMut::new();
+        for (msg_type, msg) in &msgs {
+            PlainFrameCodec.encode((*msg_type, msg.as_slice()), &mut bytes).unwrap();
+        }
+        (bytes, msgs)
+    }
+
+    /// feed `bytes` to `codec` `chunk` bytes at a time, decoding everything available after each chunk
+    fn decode_chunked<D: Decoder>(codec: &mut D, bytes: &[u8], chunk: usize) -> Vec<D::Item>
+    where D::Error: std::fmt::Debug {
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+        for chunk in bytes.chunks(chunk) {
+            buf.extend_from_slice(chunk);
+            while let Some(item) = codec.decode(&mut buf).unwrap() {
+                out.push(item);
+            }
+        }
+        assert!(buf.is_empty());
+        out
+    }
+
+    #[test]
+    fn noise_byte_by_byte() {
+        let (bytes, frames) = noise_frames();
+        let out = decode_chunked(&mut NoiseFrameCodec, &bytes, 1);
+        assert_eq!(out.iter().map(|f| f.to_vec()).collect::<Vec<_>>(), frames);
+    }
+
+    #[test]
+    fn noise_coalesced() {
+        let (bytes, frames) = noise_frames();
+        let out = decode_chunked(&mut NoiseFrameCodec, &bytes, bytes.len());
+        assert_eq!(out.iter().map(|f| f.to_vec()).collect::<Vec<_>>(), frames);
+        let out = decode_chunked(&mut NoiseFrameCodec, &bytes, 7);
+        assert_eq!(out.len(), frames.len());
+    }
+
+    #[test]
+    fn plain_byte_by_byte() {
+        let (bytes, msgs) = plain_messages();
+        let out = decode_chunked(&mut PlainFrameCodec, &bytes, 1);
+        assert_eq!(out.into_iter().map(|(t, m)| (t, m.to_vec())).collect::<Vec<_>>(), msgs);
+    }
+
+    #[test]
+    fn plain_coalesced() {
+        let (bytes, msgs) = plain_messages();
+        let out = decode_chunked(&mut PlainFrameCodec, &bytes, bytes.len());
+        assert_eq!(out.into_iter().map(|(t, m)| (t, m.to_vec())).collect::<Vec<_>>(), msgs);
+    }
+
+    #[test]
+    fn wrong_preamble() {
+        let mut buf = BytesMut::from(&[0x00, 0x00, 0x01][..]);
+        assert!(matches!(NoiseFrameCodec.decode(&mut buf), Err(ConnectionError::FrameHadWrongPreamble(0x00))));
+        let mut buf = BytesMut::from(&[0x01, 0x00, 0x01][..]);
+        assert!(matches!(PlainFrameCodec.decode(&mut buf), Err(ConnectionError::FrameHadWrongPreamble(0x01))));
+    }
+
+    #[test]
+    fn message_type_too_large() {
+        // type 65537 (would be 1 as a u16)
+        let mut buf = BytesMut::from(&[0x00, 0x00, 0x81, 0x80, 0x04][..]);
+        assert!(matches!(PlainFrameCodec.decode(&mut buf), Err(ConnectionError::MessageTypeTooLarge(65537))));
+    }
+}
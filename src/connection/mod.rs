@@ -1,4 +1,8 @@
 pub mod noise;
 pub mod plain;
+pub mod auto;
 pub mod base;
-mod util;
+pub mod codec;
+pub mod options;
+pub mod transport;
+pub(crate) mod util;
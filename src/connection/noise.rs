@@ -1,29 +1,54 @@
 use base64::prelude::*;
 use bytes::{Buf, BytesMut};
+use futures::{SinkExt, StreamExt};
 use memchr::memchr;
+use rand_core::{OsRng, RngCore};
 use snow::{HandshakeState, TransportState};
-use tokio::{net::TcpStream, io::{AsyncWriteExt, AsyncReadExt}};
-use std::{hash::{Hash, Hasher}, time::Duration};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::Framed;
+use std::hash::{Hash, Hasher};
 use crate::{error::ConnectionError, model::MessageType};
-use super::base::Connection;
+use super::{base::Connection, codec::{NoiseFrameCodec, NOISE_MAX_FRAME_LEN}, options::{with_timeout, ConnectionOptions, TimeoutStage}, transport::{AsyncStream, BoxedStream, Transport}, util::parse_message_type};
 
 pub const NOISE_HELLO: &[u8; 3] = b"\x01\x00\x00";
-pub const READ_TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
 pub const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
 pub const NOISE_PROLOGUE: &[u8; 14] = b"NoiseAPIInit\x00\x00";
 pub const NOISE_PSK_LEN: usize = 32;
 
+type NoiseFramed = Framed<BoxedStream, NoiseFrameCodec>;
+
+/// Generate a random noise PSK in the format ESPHome uses (32 bytes, base64)
+pub fn generate_noise_psk() -> String {
+    let mut key = [0u8; NOISE_PSK_LEN];
+    OsRng.fill_bytes(&mut key);
+    BASE64_STANDARD.encode(key)
+}
+
+/// Decode a base64 noise PSK, checking that it's exactly 32 bytes
+pub fn decode_noise_psk(noise_psk: &str) -> Result<[u8; NOISE_PSK_LEN], ConnectionError> {
+    let key = BASE64_STANDARD.decode(noise_psk).map_err(|_| ConnectionError::InvalidNoisePsk)?;
+    key.try_into().map_err(|_| ConnectionError::InvalidNoisePsk)
+}
+
 pub struct NoiseConnection {
-    pub(crate) ip: String,
-    noise_psk: String,
-    pub(crate) stream: Option<TcpStream>,
-    noise: Option<TransportState>,
+    pub(crate) transport: Transport,
+    /// tried in order until the device accepts one (current key first, then previous ones)
+    noise_psks: Vec<String>,
+    /// index into noise_psks of the key used by the current connection
+    active_psk: Option<usize>,
+    pub(crate) stream: Option<NoiseFramed>,
+    noise: Option<Box<TransportState>>,
     pub server_name: Option<String>,
+    /// refuse to connect if the server hello has a different node name
+    pub expected_name: Option<String>,
+    /// refuse to connect if `DeviceInfoResponse.mac_address` differs (checked by `ESPHomeDevice`)
+    pub expected_mac: Option<String>,
+    pub(crate) options: ConnectionOptions,
 }
 
 impl Hash for NoiseConnection {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.ip.hash(state);
+        self.transport.hash(state);
     }
 }
 
@@ -44,171 +69,304 @@ impl Connection for NoiseConnection {
         //TODO reuse buffer?
         let mut frame = BytesMut::with_capacity(frame_header.len() + msg_len);
         frame.extend_from_slice(&frame_header);
-        frame.extend_from_slice(&msg_bytes);
+        frame.extend_from_slice(msg_bytes);
 
         //encrypt frame
-        let mut eframe = BytesMut::with_capacity(65535);
-        eframe.resize(65535, 0);
+        let mut eframe = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
         let eframe_len = noise.write_message(&frame, &mut eframe)?;
         eframe.truncate(eframe_len);
 
-        //make packet
-        let packet_header = [
-            0x01,
-            (eframe_len >> 8) as u8,
-            eframe_len as u8,
-        ];
-        //TODO reuse buffer?
-        let mut packet = BytesMut::with_capacity(packet_header.len() + eframe.len());
-        packet.extend_from_slice(&packet_header);
-        packet.extend_from_slice(&eframe);
-
         //send packet
-        stream.write_all(&packet).await?;
-        stream.flush().await?;
+        stream.send(&eframe[..]).await?;
 
         Ok(())
     }
 
-    async fn receive_message(&mut self, first_byte: Option<u8>) -> Result<(MessageType, BytesMut), ConnectionError> {
+    async fn receive_message(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
         let stream = self.stream.as_mut().ok_or(ConnectionError::NotConnected)?;
+        let frame = Self::read_frame(stream).await?;
         let noise = self.noise.as_mut().ok_or(ConnectionError::NotConnected)?;
-        let frame = Self::read_frame(stream, first_byte).await?;
-        let mut msg = BytesMut::with_capacity(65535);
-        msg.resize(65535, 0);
+        let mut msg = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
         let msg_size = noise.read_message(&frame, &mut msg)?;
         msg.truncate(msg_size);
-        let msg_type_num = u16::from_be_bytes([msg[0], msg[1]]);
-        let msg_type = MessageType::from_repr(msg_type_num)
-            .ok_or(ConnectionError::UnknownMessageType(msg_type_num))?;
+        if msg.len() < 4 {
+            return Err(ConnectionError::FrameTooShort(msg.len()));
+        }
+        let msg_type = parse_message_type(u16::from_be_bytes([msg[0], msg[1]]))?;
         msg.advance(4);
         Ok((msg_type, msg))
     }
 
-    fn try_read_byte(&mut self) -> Result<Option<u8>, ConnectionError> {
-        let stream = self.stream.as_mut().ok_or(ConnectionError::NotConnected)?;
-        let mut buf = [0u8; 1];
-        if stream.try_read(&mut buf).is_ok() {
-            Ok(Some(buf[0]))
-        }
-        else {
-            Ok(None)
-        }
-    }
-
     async fn connect(&mut self) -> Result<(), ConnectionError> {
         if self.stream.is_some() {
-            return Ok(()) //TODO: is this wanted behavior... should this error? should it reconnect?
+            return Ok(())
         }
-        let mut noise_handshake = Self::setup_noise(&self.noise_psk)?;
-        let mut stream = TcpStream::connect(&self.ip).await?;
-        Self::send_hello(&mut stream, &mut noise_handshake).await?;
-        self.server_name = Some(Self::receive_hello(&mut stream).await?);
-        self.noise = Some(Self::receive_handshake(&mut stream, noise_handshake).await?);
-        self.stream = Some(stream);
-        Ok(())
+        for (i, noise_psk) in self.noise_psks.iter().enumerate() {
+            let noise_handshake = Self::setup_noise(noise_psk)?;
+            let stream = with_timeout(self.options.connect_timeout, TimeoutStage::Connect, self.transport.open()).await?;
+            let handshake = Self::handshake(Framed::new(stream, NoiseFrameCodec), noise_handshake, self.expected_name.as_deref());
+            match with_timeout(self.options.handshake_timeout, TimeoutStage::Handshake, handshake).await {
+                Ok((stream, noise, server_name)) => {
+                    self.server_name = Some(server_name);
+                    self.noise = Some(Box::new(noise));
+                    self.stream = Some(stream);
+                    self.active_psk = Some(i);
+                    return Ok(());
+                }
+                // the device rejected the key, try the next one
+                Err(ConnectionError::HandshakeHadWrongPreamble(_)) if i + 1 < self.noise_psks.len() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ConnectionError::InvalidNoisePsk)
     }
 
     async fn disconnect(&mut self) -> Result<(), ConnectionError> {
-        if let Some(stream) = &mut self.stream {
-            stream.shutdown().await?;
-        }
+        let stream = self.stream.take();
         self.noise = None;
-        self.stream = None;
         self.server_name = None;
+        self.active_psk = None;
+        if let Some(mut stream) = stream {
+            stream.get_mut().shutdown().await?;
+        }
         Ok(())
     }
 
+    fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
 }
 
 impl NoiseConnection {
+    /// Connect over TCP to `ip` (`host:port`)
     pub fn new(ip: String, noise_psk: String) -> Self {
+        Self::with_transport(Transport::Tcp(ip), noise_psk)
+    }
+
+    /// Run over an already open stream
+    pub fn from_stream(stream: impl AsyncStream + 'static, noise_psk: String) -> Self {
+        Self::with_transport(Transport::stream(stream), noise_psk)
+    }
+
+    pub fn with_transport(transport: Transport, noise_psk: String) -> Self {
         Self {
-            ip,
-            noise_psk,
+            transport,
+            noise_psks: vec![noise_psk],
+            active_psk: None,
             stream: None,
             noise: None,
             server_name: None,
+            expected_name: None,
+            expected_mac: None,
+            options: ConnectionOptions::default(),
         }
     }
 
+    pub fn with_options(mut self, options: ConnectionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Only connect to the node called `name` (guards against a different node taking over the IP)
+    pub fn with_expected_name(mut self, name: impl Into<String>) -> Self {
+        self.expected_name = Some(name.into());
+        self
+    }
+
+    /// Only connect to the node with this MAC address (`AA:BB:CC:DD:EE:FF`)
+    pub fn with_expected_mac(mut self, mac: impl Into<String>) -> Self {
+        self.expected_mac = Some(mac.into());
+        self
+    }
+
+    /// Also try these keys (in order) if the device rejects the main one (ex. the keys before a rotation)
+    pub fn with_fallback_psks(mut self, noise_psks: impl IntoIterator<Item = String>) -> Self {
+        self.noise_psks.extend(noise_psks);
+        self
+    }
+
+    /// The key the device accepted for the current connection
+    pub fn active_psk(&self) -> Option<&str> {
+        self.active_psk.map(|i| self.noise_psks[i].as_str())
+    }
+
+    /// Use `noise_psk` from now on, keeping the previous keys as fallbacks
+    pub fn set_noise_psk(&mut self, noise_psk: String) {
+        let active = self.active_psk().map(str::to_string);
+        self.noise_psks.retain(|psk| *psk != noise_psk);
+        self.noise_psks.insert(0, noise_psk);
+        self.active_psk = active.and_then(|active| self.noise_psks.iter().position(|psk| *psk == active));
+    }
+
     fn setup_noise(noise_psk: &str) -> Result<HandshakeState, ConnectionError> {
-        let mut key = [0u8; 32];
-        BASE64_STANDARD.decode_slice(noise_psk, &mut key)?;
+        let key = decode_noise_psk(noise_psk)?;
         Ok(snow::Builder::new(NOISE_PARAMS.parse()?)
             .psk(0, &key)
             .prologue(NOISE_PROLOGUE)
             .build_initiator()?)
     }
 
-    /// Send ClientHello to the server
+    /// Exchange hellos (checking the server name) and run the handshake
+    async fn handshake(
+        mut stream: NoiseFramed,
+        mut noise_handshake: HandshakeState,
+        expected_name: Option<&str>,
+    ) -> Result<(NoiseFramed, TransportState, String), ConnectionError> {
+        Self::send_hello(&mut stream, &mut noise_handshake).await?;
+        let server_name = Self::receive_hello(&mut stream).await?;
+        if let Some(expected) = expected_name && expected != server_name {
+            return Err(ConnectionError::ServerNameMismatch { expected: expected.to_string(), got: server_name });
+        }
+        let noise = Self::receive_handshake(&mut stream, noise_handshake).await?;
+        Ok((stream, noise, server_name))
+    }
+
+    /// Send ClientHello (an empty frame) and the start of the handshake to the server
     async fn send_hello(
-        stream: &mut TcpStream,
+        stream: &mut NoiseFramed,
         noise_handshake: &mut HandshakeState,
     ) -> Result<(), ConnectionError> {
-        let mut frame = BytesMut::with_capacity(65535);
-        frame.resize(65535, 0);
-        let mut frame_len = noise_handshake.write_message(&[], &mut frame)?;
-        frame.truncate(frame_len);
-        frame_len += 1;
-        let header = [
-            0x01,
-            (frame_len >> 8) as u8,
-            frame_len as u8,
-        ];
-        //TODO reuse buffer?
-        let mut message = BytesMut::with_capacity(3 + 3 + 1 + frame_len);
-        message.extend_from_slice(NOISE_HELLO);
-        message.extend_from_slice(&header);
-        message.extend_from_slice(&[0x00]);
-        message.extend_from_slice(&frame);
-        stream.write(&message).await?;
+        let mut frame = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
+        let frame_len = noise_handshake.write_message(&[], &mut frame[1..])?;
+        frame.truncate(frame_len + 1);
+        stream.feed(&[][..]).await?;
+        stream.send(&frame[..]).await?;
         Ok(())
     }
 
-    async fn receive_hello(stream: &mut TcpStream) -> Result<String, ConnectionError> {
-        let frame = Self::read_frame(stream, None).await?;
-        if frame[0] != 0x01 {
-            return Err(ConnectionError::ClientWantsUnknownNoiseProtocol(frame[0]))
+    async fn receive_hello(stream: &mut NoiseFramed) -> Result<String, ConnectionError> {
+        let frame = match Self::read_frame(stream).await {
+            // a plaintext device rejecting the noise hello
+            Err(ConnectionError::FrameHadWrongPreamble(0x00)) => return Err(ConnectionError::NotEncrypted),
+            res => res?,
+        };
+        if frame.first() != Some(&0x01) {
+            return Err(ConnectionError::ClientWantsUnknownNoiseProtocol(frame.first().copied().unwrap_or(0)))
         }
         let pos = memchr(0, &frame[1..]).ok_or(ConnectionError::MessageMissingNullTerminator)?;
         let server_name = String::from_utf8_lossy(&frame[1..pos + 1]).into_owned();
         Ok(server_name)
     }
 
-    async fn receive_handshake(stream: &mut TcpStream, mut noise_handshake: HandshakeState) -> Result<TransportState, ConnectionError> {
-        let frame = Self::read_frame(stream, None).await?;
-        if frame[0] != 0x00 {
-            return Err(ConnectionError::HandshakeHadWrongPreamble(frame[0]));
+    async fn receive_handshake(stream: &mut NoiseFramed, mut noise_handshake: HandshakeState) -> Result<TransportState, ConnectionError> {
+        let frame = Self::read_frame(stream).await?;
+        if frame.first() != Some(&0x00) {
+            return Err(ConnectionError::HandshakeHadWrongPreamble(frame.first().copied().unwrap_or(0)));
         }
         noise_handshake.read_message(&frame[1..], &mut [])?;
         Ok(noise_handshake.into_transport_mode()?)
     }
 
-    async fn read_frame(stream: &mut TcpStream, first_byte: Option<u8>) -> Result<BytesMut, ConnectionError> {
-        let frame_size;
-        if let Some(first_byte) = first_byte {
-            if first_byte != 0x01 {
-                return Err(ConnectionError::FrameHadWrongPreamble(first_byte));
-            }
-            let mut header = [0u8; 2];
-            stream.read_exact(&mut header).await?;
-            frame_size = u16::from_be_bytes([header[0], header[1]]) as usize;
-        }
-        else {
-            let mut header = [0u8; 3];
-            stream.read_exact(&mut header).await?;
-            if header[0] != 0x01 {
-                return Err(ConnectionError::FrameHadWrongPreamble(header[0]));
-            }
-            frame_size = u16::from_be_bytes([header[1], header[2]]) as usize;
-        }
-
-        let mut frame = BytesMut::with_capacity(frame_size);
-        let size = stream.read_buf(&mut frame).await?;
-        frame.truncate(size);
-        Ok(frame)
+    /// Read a whole frame (cancel safe)
+    async fn read_frame(stream: &mut NoiseFramed) -> Result<BytesMut, ConnectionError> {
+        stream.next().await.unwrap_or(Err(ConnectionError::ConnectionClosed))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use crate::api;
+    use crate::connection::plain::PlainConnection;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::test_util::*;
+
+    #[test]
+    fn psk() {
+        let psk = generate_noise_psk();
+        assert_ne!(psk, generate_noise_psk());
+        assert_eq!(BASE64_STANDARD.encode(decode_noise_psk(&psk).unwrap()), psk);
+        assert!(matches!(decode_noise_psk("c2hvcnQ="), Err(ConnectionError::InvalidNoisePsk)));
+        assert!(matches!(decode_noise_psk("not base64!"), Err(ConnectionError::InvalidNoisePsk)));
+    }
+
+    #[test]
+    fn rotate() {
+        let mut conn = NoiseConnection::new(String::new(), "a".to_string()).with_fallback_psks(["b".to_string()]);
+        conn.active_psk = Some(1);
+        conn.set_noise_psk("c".to_string());
+        assert_eq!(conn.noise_psks, ["c", "a", "b"]);
+        assert_eq!(conn.active_psk(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn noise_connect() {
+        let (_mock, mut dev) = connected_noise(mock_builder()).await;
+
+        assert_eq!(dev.get_light_key_from_name("rgbct_bulb"), Some(1));
+        assert_eq!(dev.get_switch_key_from_name("relay"), Some(2));
+        let info = timeout(TIMEOUT, dev.device_info()).await.unwrap().unwrap();
+        assert_eq!(info.name, "mock_bulb");
+
+        timeout(TIMEOUT, dev.ping_wait()).await.unwrap().unwrap();
+        assert!(dev.last_ping.is_some());
+        timeout(TIMEOUT, dev.disconnect()).await.unwrap().unwrap();
+        assert!(!dev.connected);
+    }
+
+    #[tokio::test]
+    async fn noise_psk_rotation() {
+        let mock = mock_builder().noise_psk(NOISE_PSK).start().await.unwrap();
+        let conn = NoiseConnection::new(mock.addr(), generate_noise_psk()).with_fallback_psks([NOISE_PSK.to_string()]);
+        let handle = ESPHomeDevice::new(conn.into(), None).spawn();
+        timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+        assert_eq!(handle.active_noise_psk().await.unwrap().as_deref(), Some(NOISE_PSK));
+
+        let new_psk = generate_noise_psk();
+        timeout(TIMEOUT, handle.set_noise_psk(&new_psk)).await.unwrap().unwrap();
+        let res = timeout(TIMEOUT, handle.set_noise_psk("c2hvcnQ=")).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::InvalidNoisePsk))));
+
+        //reconnect: the old key no longer works, the new one is tried first
+        timeout(TIMEOUT, handle.disconnect()).await.unwrap().unwrap();
+        timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+        assert_eq!(handle.active_noise_psk().await.unwrap(), Some(new_psk.clone()));
+
+        let mut dev = ESPHomeDevice::new_noise(mock.addr(), NOISE_PSK.to_string());
+        assert!(timeout(TIMEOUT, dev.connect()).await.unwrap().is_err());
+
+        let (plain_mock, mut dev) = connected_plain(mock_builder()).await;
+        let res = timeout(TIMEOUT, dev.set_noise_psk(&new_psk)).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::NoisePskRejected)));
 
+        //a device which never answers
+        let options = ConnectionOptions { request_timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let handle = ESPHomeDevice::new(PlainConnection::new(plain_mock.addr()).with_options(options).into(), None).spawn();
+        timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+        plain_mock.set_unresponsive(true);
+        let res = timeout(TIMEOUT, handle.set_noise_psk(&new_psk)).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Request })));
+    }
+
+    #[tokio::test]
+    async fn noise_pinning() {
+        let mock = mock_builder()
+            .noise_psk(NOISE_PSK)
+            .device_info(api::DeviceInfoResponse { mac_address: "AA:BB:CC:DD:EE:01".to_string(), ..Default::default() })
+            .start().await.unwrap();
+
+        let conn = NoiseConnection::new(mock.addr(), NOISE_PSK.to_string()).with_expected_name("kitchen");
+        let mut dev = ESPHomeDevice::new(conn.into(), None);
+        match timeout(TIMEOUT, dev.connect()).await.unwrap() {
+            Err(DeviceError::ConnectionError(ConnectionError::ServerNameMismatch { got, .. })) => assert_eq!(got, "mock_bulb"),
+            res => panic!("unexpected {res:?}"),
+        }
+
+        let conn = NoiseConnection::new(mock.addr(), NOISE_PSK.to_string()).with_expected_mac("aa:bb:cc:dd:ee:02");
+        let mut dev = ESPHomeDevice::new(conn.into(), None);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::ConnectionError(ConnectionError::MacAddressMismatch { .. }))));
+        assert!(dev.entities.light.is_empty());
+        assert!(!dev.connected);
+
+        let conn = NoiseConnection::new(mock.addr(), NOISE_PSK.to_string())
+            .with_expected_name("mock_bulb")
+            .with_expected_mac("aa-bb-cc-dd-ee-01");
+        let mut dev = ESPHomeDevice::new(conn.into(), None);
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(dev.entities.light.len(), 1);
+    }
+}
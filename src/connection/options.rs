@@ -0,0 +1,128 @@
+use std::{fmt, future::Future, time::Duration};
+use crate::error::ConnectionError;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const READ_TIMEOUT: Duration = Duration::from_secs(20);
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What took too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeoutStage {
+    /// opening the stream
+    Connect,
+    /// the noise handshake
+    Handshake,
+    /// a single read while waiting for a response
+    Read,
+    /// a whole request (`transaction`)
+    Request,
+}
+
+impl fmt::Display for TimeoutStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self {
+            Self::Connect => "connect",
+            Self::Handshake => "handshake",
+            Self::Read => "read",
+            Self::Request => "request",
+        };
+        f.write_str(stage)
+    }
+}
+
+/// Timeouts of a connection (`None` waits forever)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionOptions {
+    pub connect_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    /// between messages while waiting for a response (not while idle, see `Keepalive`)
+    pub read_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Some(CONNECT_TIMEOUT),
+            handshake_timeout: Some(HANDSHAKE_TIMEOUT),
+            read_timeout: Some(READ_TIMEOUT),
+            request_timeout: Some(REQUEST_TIMEOUT),
+        }
+    }
+}
+
+/// Run `fut`, failing with `ConnectionError::Timeout(stage)` if it takes longer than `duration`
+pub(crate) async fn with_timeout<T, E: From<ConnectionError>>(
+    duration: Option<Duration>,
+    stage: TimeoutStage,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, fut).await
+            .unwrap_or_else(|_| Err(ConnectionError::Timeout(stage).into())),
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::connection::noise::NoiseConnection;
+    use crate::connection::plain::PlainConnection;
+    use crate::connection::transport::Transport;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::model::ConnectionState;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn timeouts() {
+        let short = Some(Duration::from_millis(100));
+        let options = ConnectionOptions { connect_timeout: short, handshake_timeout: short, read_timeout: short, request_timeout: short };
+
+        let conn = PlainConnection::with_transport(Transport::connector(|| Box::pin(futures::future::pending())));
+        let mut dev = ESPHomeDevice::new(conn.into(), None).with_connection_options(options);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Connect })));
+
+        // accepts, but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let mut dev = ESPHomeDevice::new(NoiseConnection::new(addr.clone(), NOISE_PSK.to_string()).with_options(options).into(), None);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Handshake })));
+
+        let read_first = ConnectionOptions { request_timeout: Some(Duration::from_secs(1)), ..options };
+        let mut dev = ESPHomeDevice::new(PlainConnection::new(addr.clone()).with_options(read_first).into(), None);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Read })));
+
+        let request_only = ConnectionOptions { read_timeout: None, ..options };
+        let mut dev = ESPHomeDevice::new(PlainConnection::new(addr).with_options(request_only).into(), None);
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::Timeout { stage: TimeoutStage::Request })));
+
+        //a connected device which stops answering times out the request, but keeps the connection
+        let mock = mock_builder().start().await.unwrap();
+        for (options, stage) in [(read_first, TimeoutStage::Read), (request_only, TimeoutStage::Request)] {
+            let mut dev = ESPHomeDevice::new(PlainConnection::new(mock.addr()).with_options(options).into(), None);
+            timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+            mock.set_unresponsive(true);
+            let res = timeout(TIMEOUT, dev.device_info()).await.unwrap();
+            assert!(matches!(res, Err(DeviceError::Timeout { stage: s }) if s == stage), "{res:?}");
+            assert!(dev.connected);
+            assert!(matches!(*dev.watch_connection_state().borrow(), ConnectionState::Connected));
+            mock.set_unresponsive(false);
+            assert_eq!(timeout(TIMEOUT, dev.device_info()).await.unwrap().unwrap().name, "mock_bulb");
+        }
+    }
+}
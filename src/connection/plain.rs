@@ -1,92 +1,102 @@
-use bytes::{BytesMut, BufMut};
-use tokio::io::AsyncReadExt;
-use tokio::{net::TcpStream, io::AsyncWriteExt};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::Framed;
 use std::hash::{Hash, Hasher};
 use crate::{error::ConnectionError, model::MessageType};
-use super::base::Connection;
-use super::util::{varu32_to_bytes, Varu32};
+use super::{base::Connection, codec::PlainFrameCodec, util::parse_message_type, options::{with_timeout, ConnectionOptions, TimeoutStage}, transport::{AsyncStream, BoxedStream, Transport}};
 
-///NOTE UNTESTED!!!!!!!!
 pub struct PlainConnection {
-    pub(crate) ip: String,
-    pub(crate) stream: Option<TcpStream>,
+    pub(crate) transport: Transport,
+    pub(crate) stream: Option<Framed<BoxedStream, PlainFrameCodec>>,
+    pub(crate) options: ConnectionOptions,
 }
 
 impl Hash for PlainConnection {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.ip.hash(state);
+        self.transport.hash(state);
     }
 }
 
 impl Connection for PlainConnection {
     async fn send_message(&mut self, msg_type: MessageType, msg_bytes: &BytesMut) -> Result<(), ConnectionError> {
         let stream = self.stream.as_mut().ok_or(ConnectionError::NotConnected)?;
-
-        let msg_type_var = varu32_to_bytes(msg_type as u32);
-        let msg_len = msg_bytes.len();
-        let msg_len_var = varu32_to_bytes(msg_len as u32);
-
-        let mut packet = BytesMut::with_capacity(msg_len + 1 + msg_type_var.len() + msg_len_var.len());
-        packet.put_u8(0);
-        packet.extend_from_slice(&msg_type_var);
-        packet.extend_from_slice(&msg_len_var);
-        packet.extend_from_slice(&msg_bytes);
-
-        stream.write_all(&packet).await?;
-        stream.flush().await?;
-
+        stream.send((msg_type as u16, &msg_bytes[..])).await?;
         Ok(())
     }
 
-    async fn receive_message(&mut self, first_byte: Option<u8>) -> Result<(MessageType, BytesMut), ConnectionError> {
+    async fn receive_message(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
         let stream = self.stream.as_mut().ok_or(ConnectionError::NotConnected)?;
-        let preamble = stream.read_varu32(first_byte).await?;
-        if preamble != 0x00 {
-            return Err(ConnectionError::FrameHadWrongPreamble(preamble as u8))
-        }
-
-        let msg_len = stream.read_varu32(None).await? as usize;
-        let msg_type_num = stream.read_varu32(None).await? as u16;
-        let msg_type = MessageType::from_repr(msg_type_num)
-            .ok_or(ConnectionError::UnknownMessageType(msg_type_num))?;
-        let mut msg = BytesMut::with_capacity(msg_len);
-        stream.read_buf(&mut msg).await?;
-        Ok((msg_type, msg))
-    }
-
-    fn try_read_byte(&mut self) -> Result<Option<u8>, ConnectionError> {
-        let stream = self.stream.as_mut().ok_or(ConnectionError::NotConnected)?;
-        let mut buf = [0u8; 1];
-        if stream.try_read(&mut buf).is_ok() {
-            Ok(Some(buf[0]))
-        }
-        else {
-            Ok(None)
+        match stream.next().await {
+            // a noise device rejecting the plaintext hello
+            Some(Err(ConnectionError::FrameHadWrongPreamble(0x01))) => Err(ConnectionError::EncryptionRequired),
+            Some(res) => {
+                let (msg_type_num, msg) = res?;
+                Ok((parse_message_type(msg_type_num)?, msg))
+            }
+            None => Err(ConnectionError::ConnectionClosed),
         }
     }
 
     async fn connect(&mut self) -> Result<(), ConnectionError> {
         if self.stream.is_some() {
-            return Ok(()) //TODO: is this wanted behavior... should this error? should it reconnect?
+            return Ok(())
         }
-        let stream = TcpStream::connect(&self.ip).await?;
-        self.stream = Some(stream);
+        let stream = with_timeout(self.options.connect_timeout, TimeoutStage::Connect, self.transport.open()).await?;
+        self.stream = Some(Framed::new(stream, PlainFrameCodec));
         Ok(())
     }
 
     async fn disconnect(&mut self) -> Result<(), ConnectionError> {
-        if let Some(stream) = &mut self.stream {
-            stream.shutdown().await?;
+        if let Some(mut stream) = self.stream.take() {
+            stream.get_mut().shutdown().await?;
         }
-        self.stream = None;
         Ok(())
     }
+
+    fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
 }
 
 impl PlainConnection {
+    /// Connect over TCP to `ip` (`host:port`)
     pub fn new(ip: String) -> Self {
-        Self { ip, stream: None }
+        Self::with_transport(Transport::Tcp(ip))
+    }
+
+    /// Run over an already open stream
+    pub fn from_stream(stream: impl AsyncStream + 'static) -> Self {
+        Self::with_transport(Transport::stream(stream))
+    }
+
+    pub fn with_transport(transport: Transport) -> Self {
+        Self { transport, stream: None, options: ConnectionOptions::default() }
+    }
+
+    pub fn with_options(mut self, options: ConnectionOptions) -> Self {
+        self.options = options;
+        self
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn plain_connect() {
+        let mock = mock_builder().password("secret").start().await.unwrap();
+
+        let mut dev = ESPHomeDevice::new_plain(mock.addr(), "wrong".to_string());
+        let res = timeout(TIMEOUT, dev.connect()).await.unwrap();
+        assert!(matches!(res, Err(DeviceError::InvalidPassword)));
 
+        let mut dev = ESPHomeDevice::new_plain(mock.addr(), "secret".to_string());
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(dev.entities.light.len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+use futures::future::BoxFuture;
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream};
+use std::{fmt, hash::{Hash, Hasher}, io};
+use crate::error::ConnectionError;
+
+/// Any stream a connection can run over (TCP, unix socket, `tokio::io::duplex`, tunnels, ...)
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+pub type Connector = Box<dyn Fn() -> BoxFuture<'static, io::Result<BoxedStream>> + Send + Sync>;
+
+/// Where a connection gets its stream from
+pub enum Transport {
+    /// TCP to `host:port`
+    Tcp(String),
+    /// An already open stream (can only be connected once)
+    Stream(Option<BoxedStream>),
+    /// Opens a new stream on every connect
+    Connector(Connector),
+}
+
+impl Transport {
+    pub fn stream(stream: impl AsyncStream + 'static) -> Self {
+        Self::Stream(Some(Box::new(stream)))
+    }
+
+    /// Ex. `Transport::connector(|| Box::pin(async { Ok(Box::new(UnixStream::connect(PATH).await?) as BoxedStream) }))`
+    pub fn connector<F>(f: F) -> Self
+    where
+        F: Fn() -> BoxFuture<'static, io::Result<BoxedStream>> + Send + Sync + 'static
+    {
+        Self::Connector(Box::new(f))
+    }
+
+    pub(crate) async fn open(&mut self) -> Result<BoxedStream, ConnectionError> {
+        match self {
+            Transport::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr.as_str()).await?)),
+            Transport::Stream(stream) => stream.take().ok_or(ConnectionError::StreamAlreadyUsed),
+            Transport::Connector(connector) => Ok(connector().await?),
+        }
+    }
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "Tcp({addr})"),
+            Transport::Stream(_) => write!(f, "Stream"),
+            Transport::Connector(_) => write!(f, "Connector"),
+        }
+    }
+}
+
+impl Hash for Transport {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let Transport::Tcp(addr) = self {
+            addr.hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+    use crate::connection::noise::NoiseConnection;
+    use crate::connection::plain::PlainConnection;
+    use crate::device::ESPHomeDevice;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn duplex_transport() {
+        let mock = mock_builder().noise_psk(NOISE_PSK).start().await.unwrap();
+        let conn = NoiseConnection::from_stream(mock.duplex(), NOISE_PSK.to_string());
+        let mut dev = ESPHomeDevice::new(conn.into(), None);
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        assert_eq!(dev.get_light_key_from_name("rgbct_bulb"), Some(1));
+
+        let plain_mock = mock_builder().start().await.unwrap();
+        let conn = PlainConnection::from_stream(plain_mock.duplex());
+        let mut dev = ESPHomeDevice::new(conn.into(), None);
+        timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+        timeout(TIMEOUT, dev.ping_wait()).await.unwrap().unwrap();
+    }
+}
@@ -1,44 +1,31 @@
-
 use bytes::{BytesMut, BufMut};
-use tokio::{io::{self, AsyncReadExt}, net::TcpStream};
-
-pub trait Varu32: AsyncReadExt {
-    async fn read_varu32(&mut self, first_byte: Option<u8>) -> io::Result<u32>;
-}
+use crate::{error::ConnectionError, model::MessageType};
 
-impl Varu32 for TcpStream {
-    async fn read_varu32(&mut self, first_byte: Option<u8>) -> io::Result<u32> {
-        let mut result: u32 = 0;
-        let mut shift: u32 = 0;
+/// Decode a varu32 from the start of `buf`.
+/// Returns the value and the number of bytes it used, or None if `buf` ends before the varu32
+pub fn decode_varu32(buf: &[u8]) -> Result<Option<(u32, usize)>, ConnectionError> {
+    let mut result: u32 = 0;
+    let mut shift: u32 = 0;
 
-        if let Some(byte) = first_byte {
-            result |= (byte & 0x7f) as u32;
-            shift += 7;
-
-            if byte & 0x80 == 0 {
-                return Ok(result);
-            }
+    for (i, &byte) in buf.iter().enumerate() {
+        if shift >= 32 || (shift == 28 && byte > 0x0F) {
+            return Err(ConnectionError::Varu32TooLong);
         }
 
-        loop {
-            let mut buf = [0u8; 1];
-            self.read_exact(&mut buf).await?;
-            let byte = buf[0];
-
-            result |= ((byte & 0x7f) as u32) << shift;
-            shift += 7;
+        result |= ((byte & 0x7f) as u32) << shift;
+        shift += 7;
 
-            if byte & 0x80 == 0 {
-                break;
-            }
-
-            if shift >= 32 || (shift == 28 && byte > 0x0F) {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Varu32 too long"));
-            }
+        if byte & 0x80 == 0 {
+            return Ok(Some((result, i + 1)));
         }
-
-        Ok(result)
     }
+
+    Ok(None)
+}
+
+/// The message type with this number, if this crate knows it
+pub fn parse_message_type(msg_type_num: u16) -> Result<MessageType, ConnectionError> {
+    MessageType::from_repr(msg_type_num).ok_or(ConnectionError::UnknownMessageType(msg_type_num))
 }
 
 pub fn varu32_to_bytes(mut value: u32) -> BytesMut {
@@ -1,12 +1,12 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use prost::Message;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::{sync::{mpsc, oneshot, watch}, time::{sleep, Instant}};
 use std::{
-    collections::HashMap, hash::{Hash, Hasher}, time::{SystemTime, UNIX_EPOCH}
+    collections::{HashMap, VecDeque}, hash::{Hash, Hasher}, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}
 };
 
 use crate::{
-    api, connection::{base::{AnyConnection, Connection}, noise::NoiseConnection, plain::PlainConnection}, entity::{EntityIndexLut, EntityInfos, EntityStateUpdate}, error::DeviceError, model::{Log, LogLevel, MessageType, UserService}
+    api, compat::ApiCompat, bluetooth::{advertisement::{BleAdvertisement, BLE_SUBSCRIBE_FLAG_RAW_ADVERTISEMENTS}, gatt::GattState, proxy::{BluetoothConnectionsFree, BluetoothProxyFeatures}}, camera::CameraFrame, connection::{auto::AutoConnection, base::{AnyConnection, Connection}, noise::{decode_noise_psk, NoiseConnection}, options::{with_timeout, ConnectionOptions, TimeoutStage}, plain::PlainConnection}, entity::{EntityIndexLut, EntityInfos, EntityStateUpdate, EntityStates}, error::{ConnectionError, DeviceError}, handle::DeviceHandle, homeassistant::{HomeassistantStateProvider, HomeassistantStateRequest}, keepalive::Keepalive, model::{ApiVersion, API_VERSION, ConnectionState, HomeassistantServiceCall, Log, LogLevel, MessageType, UserService}, reconnect::ReconnectPolicy, subscription::{Fanout, OverflowPolicy, Subscription}, validate::ValidateCommand, voice::{VoiceSessionStart, VOICE_ASSISTANT_SUBSCRIBE_API_AUDIO}
 };
 
 pub struct ESPHomeDevice {
@@ -15,10 +15,54 @@ pub struct ESPHomeDevice {
     pub connected: bool,
     pub entities: EntityInfos, // Ex. lights.rgbct_bulb -> EntityInfo
     pub entity_index_lut: EntityIndexLut,
+    pub(crate) states: EntityStates,
     pub services: HashMap<u32, UserService>,
-    log_tx: Option<Sender<Log>>,
-    state_update_tx: Option<Sender<EntityStateUpdate>>,
-    pub last_ping: Option<SystemTime>
+    /// API version of the device (from its HelloResponse)
+    pub api_version: Option<ApiVersion>,
+    /// describes the device's firmware (ex. "ESPHome v2024.6.0 on ESP32"), may be empty
+    pub server_info: String,
+    pub server_name: String,
+    /// called with (client, device) versions when the device uses a different minor API version
+    pub on_api_version_warning: Option<Arc<dyn Fn(ApiVersion, ApiVersion) + Send + Sync>>,
+    log_tx: Fanout<Log>,
+    log_request: Option<api::SubscribeLogsRequest>,
+    state_update_tx: Fanout<EntityStateUpdate>,
+    ha_service_tx: Fanout<HomeassistantServiceCall>,
+    pub(crate) ble_adv_tx: Fanout<BleAdvertisement>,
+    pub(crate) ble_adv_subscribed: bool,
+    /// pending GATT requests and notification streams of active BLE connections
+    pub(crate) gatt: GattState,
+    pub(crate) ble_proxy_features: Option<BluetoothProxyFeatures>,
+    pub(crate) ble_slots_tx: watch::Sender<BluetoothConnectionsFree>,
+    pub(crate) ble_slots_subscribed: bool,
+    pub(crate) camera_tx: Fanout<CameraFrame>,
+    /// partial images by camera key
+    pub(crate) camera_buffers: HashMap<u32, BytesMut>,
+    /// how often a spawned device re-requests camera streams
+    pub(crate) camera_refresh: Option<Duration>,
+    /// snapshots waiting for the next complete image of a camera key (any camera if None), oldest first
+    pub(crate) camera_waiters: VecDeque<(Option<u32>, oneshot::Sender<CameraFrame>)>,
+    pub(crate) voice_session_tx: Option<mpsc::Sender<VoiceSessionStart>>,
+    /// microphone audio of the current voice assistant session
+    pub(crate) voice_audio_tx: Option<Fanout<Bytes>>,
+    pub(crate) voice_audio_buffer_size: usize,
+    /// requests waiting for a response, oldest first
+    pending: HashMap<MessageType, VecDeque<oneshot::Sender<BytesMut>>>,
+    pub(crate) ha_state_provider: Option<Arc<dyn HomeassistantStateProvider>>,
+    /// states the device asked for (cleared on reconnect, the device asks again)
+    pub(crate) ha_state_requests: Vec<HomeassistantStateRequest>,
+    pub last_ping: Option<SystemTime>,
+    /// when the last message was received from the device
+    pub last_received: Option<Instant>,
+    /// if set, a spawned device will ping and check for a dead connection
+    pub keepalive: Option<Keepalive>,
+    state_tx: watch::Sender<ConnectionState>,
+    /// if set, a spawned device will automatically reconnect after losing its connection
+    pub reconnect_policy: Option<ReconnectPolicy>,
+    /// set when the connection was lost (not by calling disconnect)
+    pub(crate) connection_lost: bool,
+    /// if set, commands are checked against the entity's capabilities before being sent
+    pub validate_commands: bool,
 }
 
 impl Hash for ESPHomeDevice {
@@ -35,13 +79,76 @@ impl ESPHomeDevice {
             connected: false,
             entities: EntityInfos::default(),
             entity_index_lut: EntityIndexLut::default(),
+            states: EntityStates::default(),
             services: HashMap::new(),
-            log_tx: None,
-            state_update_tx: None,
-            last_ping: None
+            api_version: None,
+            server_info: String::new(),
+            server_name: String::new(),
+            on_api_version_warning: None,
+            log_tx: Fanout::default(),
+            log_request: None,
+            state_update_tx: Fanout::default(),
+            ha_service_tx: Fanout::default(),
+            ble_adv_tx: Fanout::default(),
+            ble_adv_subscribed: false,
+            gatt: GattState::default(),
+            ble_proxy_features: None,
+            ble_slots_tx: watch::Sender::new(BluetoothConnectionsFree::default()),
+            ble_slots_subscribed: false,
+            camera_tx: Fanout::default(),
+            camera_buffers: HashMap::new(),
+            camera_refresh: None,
+            camera_waiters: VecDeque::new(),
+            voice_session_tx: None,
+            voice_audio_tx: None,
+            voice_audio_buffer_size: 0,
+            pending: HashMap::new(),
+            ha_state_provider: None,
+            ha_state_requests: Vec::new(),
+            last_ping: None,
+            last_received: None,
+            keepalive: None,
+            state_tx: watch::Sender::new(ConnectionState::Disconnected),
+            reconnect_policy: None,
+            connection_lost: false,
+            validate_commands: false,
         }
     }
 
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Call `warning(client, device)` when the device's minor API version differs
+    /// (messages may have changed, newer features may be missing)
+    pub fn with_api_version_warning(mut self, warning: impl Fn(ApiVersion, ApiVersion) + Send + Sync + 'static) -> Self {
+        self.on_api_version_warning = Some(Arc::new(warning));
+        self
+    }
+
+    /// Set the connect, handshake, read and request timeouts
+    pub fn with_connection_options(mut self, options: ConnectionOptions) -> Self {
+        *self.conn.options_mut() = options;
+        self
+    }
+
+    /// Reject commands the entity doesn't support (ex. unknown select option) with `DeviceError::InvalidCommand`
+    pub fn with_command_validation(mut self) -> Self {
+        self.validate_commands = true;
+        self
+    }
+
+    /// Watch the connection state (including why the connection was lost)
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_tx.subscribe()
+    }
+
     /// helper function to create a NoiseConnection and Device
     pub fn new_noise(ip: String, noise_psk: String) -> Self {
         Self::new(NoiseConnection::new(ip, noise_psk).into(), None)
@@ -52,21 +159,80 @@ impl ESPHomeDevice {
         Self::new(PlainConnection::new(ip).into(), Some(password))
     }
 
+    /// helper function to create an AutoConnection (plaintext or noise, whatever the device uses) and Device
+    pub fn new_auto(ip: String, noise_psk: Option<String>) -> Self {
+        Self::new(AutoConnection::new(ip, noise_psk).into(), None)
+    }
+
+    /// Connect, fetch entities and services, then re-issue any active subscriptions
     pub async fn connect(&mut self) -> Result<(), DeviceError> {
         if self.connected {
-            return Ok(()) //TODO should this reconnect?
+            return Ok(())
+        }
+
+        if let Err(e) = self.try_connect().await {
+            let _ = self.conn.disconnect().await;
+            return Err(e);
+        }
+        self.connected = true;
+        self.connection_lost = false;
+        self.state_tx.send_replace(ConnectionState::Connected);
+        Ok(())
+    }
+
+    /// Disconnect, then try to connect until successful or the reconnect policy
+    /// (default if not set) runs out of attempts
+    pub async fn reconnect(&mut self) -> Result<(), DeviceError> {
+        let policy = self.reconnect_policy.clone().unwrap_or_default();
+        let _ = self.force_disconnect().await;
+        let mut attempts = 0;
+        loop {
+            match self.connect().await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    attempts += 1;
+                    if policy.exhausted(attempts) {
+                        return Err(DeviceError::ReconnectFailed(attempts, Box::new(e)));
+                    }
+                    sleep(policy.delay(attempts)).await;
+                }
+            }
         }
+    }
 
+    async fn try_connect(&mut self) -> Result<(), DeviceError> {
         self.conn.connect().await?;
-        let _: api::HelloResponse = self.transaction(
+        self.ble_proxy_features = None;
+        let hello: api::HelloResponse = self.transaction(
             MessageType::HelloRequest,
             &api::HelloRequest {
                 client_info: "iron-esphome".to_string(),
-                api_version_major: 1,
-                api_version_minor: 9,
+                api_version_major: API_VERSION.major,
+                api_version_minor: API_VERSION.minor,
             },
             MessageType::HelloResponse,
         ).await?;
+        let version = ApiVersion::new(hello.api_version_major, hello.api_version_minor);
+        if version.major != API_VERSION.major {
+            // the connection must be closed without a disconnect request
+            self.conn.disconnect().await?;
+            return Err(DeviceError::ApiVersionMismatch { client: API_VERSION, device: version });
+        }
+        if version.minor != API_VERSION.minor && let Some(warn) = &self.on_api_version_warning {
+            warn(API_VERSION, version);
+        }
+        self.api_version = Some(version);
+        self.server_info = hello.server_info;
+        self.server_name = hello.name;
+        if let Some(expected) = self.conn.expected_mac() {
+            let expected = expected.to_string();
+            let got = self.device_info().await?.mac_address;
+            if normalize_mac(&expected) != normalize_mac(&got) {
+                // don't send the password to a node we don't trust
+                self.conn.disconnect().await?;
+                return Err(ConnectionError::MacAddressMismatch { expected, got }.into());
+            }
+        }
         let res = self.transaction::<api::ConnectResponse>(
             MessageType::ConnectRequest,
             &api::ConnectRequest {
@@ -74,16 +240,140 @@ impl ESPHomeDevice {
             },
             MessageType::ConnectResponse,
         ).await;
-        if let Ok(msg) = res {
-            if msg.invalid_password {
-                return Err(DeviceError::InvalidPassword);
-            }
+        if let Ok(msg) = res && msg.invalid_password {
+            return Err(DeviceError::InvalidPassword);
         }
         self.fetch_entities_and_services().await?;
-        self.connected = true;
+        self.resubscribe().await
+    }
+
+    /// re-issue subscriptions after (re)connecting
+    async fn resubscribe(&mut self) -> Result<(), DeviceError> {
+        if self.state_update_tx.has_subscribers() {
+            self.send(MessageType::SubscribeStatesRequest, &api::SubscribeStatesRequest {}).await?;
+        }
+        if let Some(req) = self.log_request {
+            self.send(MessageType::SubscribeLogsRequest, &req).await?;
+        }
+        if self.ha_service_tx.has_subscribers() {
+            self.send(MessageType::SubscribeHomeassistantServicesRequest, &api::SubscribeHomeassistantServicesRequest {}).await?;
+        }
+        self.ble_adv_subscribed = self.ble_adv_tx.has_subscribers();
+        if self.ble_adv_subscribed {
+            self.send(
+                MessageType::SubscribeBluetoothLEAdvertisementsRequest,
+                &api::SubscribeBluetoothLeAdvertisementsRequest { flags: BLE_SUBSCRIBE_FLAG_RAW_ADVERTISEMENTS },
+            ).await?;
+        }
+        if self.ble_slots_subscribed {
+            self.send(MessageType::SubscribeBluetoothConnectionsFreeRequest, &api::SubscribeBluetoothConnectionsFreeRequest {}).await?;
+        }
+        self.camera_buffers.clear();
+        if self.camera_refresh.is_some() && self.camera_tx.has_subscribers() {
+            self.send(MessageType::CameraImageRequest, &api::CameraImageRequest { single: false, stream: true }).await?;
+        }
+        self.voice_audio_tx = None;
+        if self.voice_session_tx.as_ref().is_some_and(|tx| !tx.is_closed()) {
+            self.send(MessageType::SubscribeVoiceAssistantRequest, &api::SubscribeVoiceAssistantRequest {
+                subscribe: true,
+                flags: VOICE_ASSISTANT_SUBSCRIBE_API_AUDIO,
+            }).await?;
+        }
+        if self.ha_state_provider.is_some() {
+            self.ha_state_requests.clear();
+            self.send(MessageType::SubscribeHomeAssistantStatesRequest, &api::SubscribeHomeAssistantStatesRequest {}).await?;
+        }
         Ok(())
     }
 
+    /// Mark the device as disconnected after the connection failed because of `reason`
+    pub(crate) async fn on_connection_lost(&mut self, reason: Arc<DeviceError>) {
+        if self.connected {
+            self.connected = false;
+            self.connection_lost = true;
+            self.state_tx.send_replace(ConnectionState::Lost(reason));
+            self.gatt.reset();
+            self.pending.clear();
+            self.camera_waiters.clear();
+            let _ = self.conn.disconnect().await;
+        }
+    }
+
+    /// Passes through `res`, handling connection loss if the connection failed while connected
+    async fn check_conn<T>(&mut self, res: Result<T, ConnectionError>) -> Result<T, DeviceError> {
+        match res {
+            Ok(v) => Ok(v),
+            Err(e) if self.connected && e.is_connection_lost() => {
+                let e = Arc::new(DeviceError::from(e));
+                self.on_connection_lost(e.clone()).await;
+                Err(DeviceError::ConnectionLost(e))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// `check_conn` for a received message, None if it was skipped because of its unknown type (ex. added by a newer ESPHome)
+    async fn check_received(&mut self, res: Result<(MessageType, BytesMut), ConnectionError>) -> Result<Option<(MessageType, BytesMut)>, DeviceError> {
+        let res = match res {
+            Err(ConnectionError::UnknownMessageType(_)) => Ok(None),
+            res => res.map(Some),
+        };
+        let msg = self.check_conn(res).await?;
+        self.last_received = Some(Instant::now());
+        Ok(msg)
+    }
+
+    /// Wait for the next message (cancel safe while the connection is healthy)
+    pub(crate) async fn receive_raw(&mut self) -> Result<(MessageType, BytesMut), DeviceError> {
+        loop {
+            let res = self.conn.receive_message().await;
+            if let Some(msg) = self.check_received(res).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Wait for the next message while expecting a response, failing after the read timeout.
+    /// A timeout keeps the connection (the device may only be slow to answer).
+    pub(crate) async fn receive_expected(&mut self) -> Result<(MessageType, BytesMut), DeviceError> {
+        loop {
+            let res = self.conn.receive_message_timeout().await;
+            if let Some(msg) = self.check_received(res).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Read the next message if one was already received
+    async fn try_receive_raw(&mut self) -> Result<Option<(MessageType, BytesMut)>, DeviceError> {
+        loop {
+            let Some(res) = self.conn.try_receive_message().transpose() else {
+                return Ok(None)
+            };
+            if let Some(msg) = self.check_received(res).await? {
+                return Ok(Some(msg));
+            }
+        }
+    }
+
+    /// Ping the device, or if nothing was received within the keepalive timeout,
+    /// mark the device as disconnected and return `KeepaliveTimeout`.
+    /// Does nothing if not connected or keepalive is not set.
+    pub async fn check_keepalive(&mut self) -> Result<(), DeviceError> {
+        let Some(keepalive) = &self.keepalive else {
+            return Ok(())
+        };
+        if !self.connected {
+            return Ok(())
+        }
+        let since = self.last_received.map(|t| t.elapsed()).unwrap_or_default();
+        if since >= keepalive.timeout {
+            self.on_connection_lost(Arc::new(DeviceError::KeepaliveTimeout(since))).await;
+            return Err(DeviceError::KeepaliveTimeout(since));
+        }
+        self.ping().await
+    }
+
     /// Ping without waiting for response
     pub async fn ping(&mut self) -> Result<(), DeviceError> {
         self.send(
@@ -94,7 +384,6 @@ impl ESPHomeDevice {
 
     /// Ping and wait for response
     pub async fn ping_wait(&mut self) -> Result<(), DeviceError> {
-        self.process_incoming().await?;
         let _: api::PingResponse = self.transaction(
             MessageType::PingRequest,
             &api::PingRequest {},
@@ -106,7 +395,6 @@ impl ESPHomeDevice {
 
     /// Send disconnect request to device, wait for response, then disconnect socket
     pub async fn disconnect(&mut self) -> Result<(), DeviceError> {
-        self.process_incoming().await?;
         let _: api::DisconnectResponse = self.transaction(
             MessageType::DisconnectRequest,
             &api::DisconnectRequest {},
@@ -117,13 +405,16 @@ impl ESPHomeDevice {
 
     /// Disconnect socket (without sending disconnect request to device)
     pub async fn force_disconnect(&mut self) -> Result<(), DeviceError> {
-        self.conn.disconnect().await?;
         self.connected = false;
+        self.connection_lost = false;
+        self.state_tx.send_replace(ConnectionState::Disconnected);
+        self.gatt.reset();
+        self.pending.clear();
+        self.conn.disconnect().await?;
         Ok(())
     }
 
     pub async fn device_info(&mut self) -> Result<api::DeviceInfoResponse, DeviceError> {
-        self.process_incoming().await?;
         let res: api::DeviceInfoResponse = self.transaction(
             MessageType::DeviceInfoRequest,
             &api::DeviceInfoRequest {},
@@ -132,45 +423,162 @@ impl ESPHomeDevice {
         Ok(res)
     }
 
+    pub(crate) async fn request_noise_psk_change(&mut self, noise_psk: &str) -> Result<oneshot::Receiver<BytesMut>, DeviceError> {
+        let key = decode_noise_psk(noise_psk)?;
+        self.request(
+            MessageType::NoiseEncryptionSetKeyRequest,
+            &api::NoiseEncryptionSetKeyRequest { key: key.to_vec() },
+            MessageType::NoiseEncryptionSetKeyResponse,
+        ).await
+    }
+
+    /// after the device saved the new key, use it for future connections (keeping the old one as a fallback)
+    pub(crate) fn on_noise_psk_changed(&mut self, noise_psk: &str, success: bool) -> Result<(), DeviceError> {
+        if !success {
+            return Err(DeviceError::NoisePskRejected);
+        }
+        if let Some(conn) = self.conn.noise_mut() {
+            conn.set_noise_psk(noise_psk.to_string());
+        }
+        Ok(())
+    }
+
+    /// Change the device's noise PSK (see `generate_noise_psk`).
+    /// The device saves it, the connection keeps using the old key until reconnecting.
+    pub async fn set_noise_psk(&mut self, noise_psk: &str) -> Result<(), DeviceError> {
+        let res: api::NoiseEncryptionSetKeyResponse = with_timeout(self.request_timeout(), TimeoutStage::Request, async {
+            let rx = self.request_noise_psk_change(noise_psk).await?;
+            self.wait_for_reply(rx).await
+        }).await?;
+        self.on_noise_psk_changed(noise_psk, res.success)
+    }
+
+    /// The noise PSK the device accepted for the current connection (None for plaintext)
+    pub fn active_noise_psk(&self) -> Option<String> {
+        self.conn.noise().and_then(|conn| conn.active_psk()).map(str::to_string)
+    }
+
+    /// How long a request may take (see `ConnectionOptions::request_timeout`)
+    pub(crate) fn request_timeout(&self) -> Option<Duration> {
+        self.conn.options().request_timeout
+    }
+
+    /// Send `req` and register for the next `res_type` message (see `wait_for_reply` and `reply`)
+    pub(crate) async fn request(
+        &mut self,
+        req_type: MessageType,
+        req: &impl prost::Message,
+        res_type: MessageType,
+    ) -> Result<oneshot::Receiver<BytesMut>, DeviceError> {
+        self.send(req_type, req).await?;
+        Ok(self.expect(res_type))
+    }
+
+    /// Register for the next `res_type` message
+    fn expect(&mut self, res_type: MessageType) -> oneshot::Receiver<BytesMut> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.entry(res_type).or_default().push_back(tx);
+        rx
+    }
+
+    /// Read messages until `rx` is answered, handling everything else as usual
+    pub(crate) async fn wait_for_reply<U: prost::Message + Default>(&mut self, rx: oneshot::Receiver<BytesMut>) -> Result<U, DeviceError> {
+        let msg = self.read_until_reply(rx, true).await?;
+        Ok(U::decode(msg)?)
+    }
+
+    /// Like `wait_for_reply`, but without the read timeout (for replies which take a while, ex. announcements)
+    pub(crate) async fn wait_for_slow_reply<U: prost::Message + Default>(&mut self, rx: oneshot::Receiver<BytesMut>) -> Result<U, DeviceError> {
+        let msg = self.read_until_reply(rx, false).await?;
+        Ok(U::decode(msg)?)
+    }
+
+    /// Read messages until `rx` is answered (by `handle_message`), optionally failing after the read timeout
+    pub(crate) async fn read_until_reply<T>(&mut self, mut rx: oneshot::Receiver<T>, read_timeout: bool) -> Result<T, DeviceError> {
+        loop {
+            match rx.try_recv() {
+                Ok(reply) => return Ok(reply),
+                Err(oneshot::error::TryRecvError::Closed) => return Err(DeviceError::NotConnected),
+                Err(oneshot::error::TryRecvError::Empty) => {}
+            }
+            let (msg_type, msg) = match read_timeout {
+                true => self.receive_expected().await?,
+                false => self.receive_raw().await?,
+            };
+            self.handle_other_message(msg_type, msg).await?;
+        }
+    }
+
+    /// Handle a message which arrived while waiting for something else.
+    /// Errors about it (ex. unknown entity) are ignored unless the connection can't be used anymore.
+    async fn handle_other_message(&mut self, msg_type: MessageType, msg: BytesMut) -> Result<(), DeviceError> {
+        match self.handle_message(msg_type, msg).await {
+            Err(e) if e.is_connection_error() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Answer the oldest request still waiting for a `msg_type` message, giving `msg` back if there is none
+    fn resolve_pending(&mut self, msg_type: &MessageType, mut msg: BytesMut) -> Option<BytesMut> {
+        let Some(waiters) = self.pending.get_mut(msg_type) else {
+            return Some(msg);
+        };
+        while let Some(tx) = waiters.pop_front() {
+            match tx.send(msg) {
+                Ok(()) => return None,
+                // gave up waiting (ex. timed out)
+                Err(unsent) => msg = unsent,
+            }
+        }
+        Some(msg)
+    }
+
     /// Request device to send state updates.
-    /// Returns a mpsc channel (of `buffer_size`) where state updates will be send
-    /// Note: Will only read from the socket when calling directly calling process_incoming or
-    ///       indirectly through certain commands which call process_incoming (ex ping_wait)
-    pub async fn subscribe_states(&mut self, buffer_size: usize) -> Result<Receiver<EntityStateUpdate>, DeviceError> {
-        let (tx, rx) = mpsc::channel(buffer_size);
-        self.state_update_tx = Some(tx);
-        self.send(
-            MessageType::SubscribeStatesRequest,
-            &api::SubscribeStatesRequest {},
-        ).await?;
-        Ok(rx)
+    /// Returns a stream of state updates, buffering up to `buffer_size` updates (handled according to `policy` when full).
+    /// Can be called multiple times, every subscription gets every update.
+    /// Note: a spawned device (see `spawn`) reads from the socket continuously. Otherwise updates are only read
+    ///       inside `process_incoming` or while waiting for the reply to a request (ex. `ping_wait`)
+    pub async fn subscribe_states(&mut self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<EntityStateUpdate>, DeviceError> {
+        let already_subscribed = self.state_update_tx.has_subscribers();
+        let sub = self.state_update_tx.subscribe(buffer_size, policy);
+        if !already_subscribed {
+            self.send(
+                MessageType::SubscribeStatesRequest,
+                &api::SubscribeStatesRequest {},
+            ).await?;
+        }
+        Ok(sub)
     }
 
     /// Request device to send logs.
-    /// Returns a mpsc channel (of `buffer_size`) where logs will be send
-    /// Note: Will only read from the socket when calling directly calling process_incoming or
-    ///       indirectly through other commands (which call process_incoming first)
-    pub async fn subscribe_logs(&mut self, level: LogLevel, dump_config: bool, buffer_size: usize) -> Result<Receiver<Log>, DeviceError> {
-        let (tx, rx) = mpsc::channel(buffer_size);
-        self.log_tx = Some(tx);
-        self.send(
-            MessageType::SubscribeLogsRequest,
-            &api::SubscribeLogsRequest { level: level as i32, dump_config },
-        ).await?;
-        Ok(rx)
+    /// Returns a stream of logs, buffering up to `buffer_size` logs (handled according to `policy` when full).
+    /// Can be called multiple times, the device uses the `level` of the latest call for all subscriptions.
+    /// Note: a spawned device (see `spawn`) reads from the socket continuously. Otherwise logs are only read
+    ///       inside `process_incoming` or while waiting for the reply to a request (ex. `ping_wait`)
+    pub async fn subscribe_logs(&mut self, level: LogLevel, dump_config: bool, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<Log>, DeviceError> {
+        let req = api::SubscribeLogsRequest { level: level as i32, dump_config };
+        let sub = self.log_tx.subscribe(buffer_size, policy);
+        self.log_request = Some(req);
+        self.send(MessageType::SubscribeLogsRequest, &req).await?;
+        Ok(sub)
     }
 
-    pub async fn execute_service(&mut self, req: &api::ExecuteServiceRequest) -> Result<(), DeviceError> {
-        self.send(MessageType::ExecuteServiceRequest, req).await
+    /// Request device to send Home Assistant service calls and events (from `homeassistant.service`/`homeassistant.event` actions).
+    /// Returns a stream of calls, buffering up to `buffer_size` calls (handled according to `policy` when full).
+    pub async fn subscribe_homeassistant_services(&mut self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<HomeassistantServiceCall>, DeviceError> {
+        let already_subscribed = self.ha_service_tx.has_subscribers();
+        let sub = self.ha_service_tx.subscribe(buffer_size, policy);
+        if !already_subscribed {
+            self.send(
+                MessageType::SubscribeHomeassistantServicesRequest,
+                &api::SubscribeHomeassistantServicesRequest {},
+            ).await?;
+        }
+        Ok(sub)
     }
 
-    pub async fn get_camera_image(&mut self, req: &api::CameraImageRequest) -> Result<api::CameraImageResponse, DeviceError> {
-        self.process_incoming().await?;
-        self.transaction(
-            MessageType::CameraImageRequest,
-            req,
-            MessageType::CameraImageResponse,
-        ).await
+    pub async fn execute_service(&mut self, req: &api::ExecuteServiceRequest) -> Result<(), DeviceError> {
+        self.send(MessageType::ExecuteServiceRequest, req).await
     }
 
     pub async fn send(&mut self, msg_type: MessageType, msg: &impl prost::Message) -> Result<(), DeviceError> {
@@ -178,16 +586,16 @@ impl ESPHomeDevice {
         let mut bytes = BytesMut::with_capacity(msg_len);
         msg.encode(&mut bytes)?;
         bytes.truncate(msg_len);
-        self.conn.send_message(msg_type, &bytes).await?;
-        Ok(())
+        let res = self.conn.send_message(msg_type, &bytes).await;
+        self.check_conn(res).await
     }
 
+    /// Wait for the next `expected_msg_type` message, handling everything else as usual (see `transaction` to send a request first)
     pub async fn recieve<U: prost::Message + Default>(&mut self, expected_msg_type: MessageType) -> Result<U, DeviceError> {
-        let (msg_type, mut msg) = self.conn.receive_message(None).await?;
-        if msg_type != expected_msg_type {
-            return Err(DeviceError::WrongMessageType(msg_type));
-        }
-        Ok(U::decode(&mut msg)?)
+        with_timeout(self.request_timeout(), TimeoutStage::Request, async {
+            let rx = self.expect(expected_msg_type);
+            self.wait_for_reply(rx).await
+        }).await
     }
 
     pub async fn transaction<U: prost::Message + Default>(
@@ -196,75 +604,138 @@ impl ESPHomeDevice {
         req: &impl prost::Message,
         res_type: MessageType,
     ) -> Result<U, DeviceError> {
-        self.send(req_type, req).await?;
-        Ok(self.recieve(res_type).await?)
+        with_timeout(self.request_timeout(), TimeoutStage::Request, async {
+            let rx = self.request(req_type, req, res_type).await?;
+            self.wait_for_reply(rx).await
+        }).await
     }
 
+    /// Handle every message which was already received (without waiting for more)
     pub async fn process_incoming(&mut self) -> Result<(), DeviceError> {
-        while let Some(first_byte) = self.conn.try_read_byte()? {
-            let (msg_type, msg) = self.conn.receive_message(Some(first_byte)).await?;
+        while let Some((msg_type, msg)) = self.try_receive_raw().await? {
+            self.handle_message(msg_type, msg).await?;
+        }
+        Ok(())
+    }
 
-            match msg_type {
-                MessageType::DisconnectRequest => {
-                    self.send(
-                        MessageType::DisconnectResponse,
-                        &api::DisconnectResponse {},
-                    ).await?;
-                    self.conn.disconnect().await?;
-                    return Err(DeviceError::DeviceRequestShutdown);
-                }
-                MessageType::PingRequest => {
-                    self.send(MessageType::PingResponse, &api::PingResponse {}).await?;
-                }
-                MessageType::PingResponse => {
-                    self.last_ping = Some(SystemTime::now());
-                }
-                MessageType::GetTimeRequest => {
-                    self.send(
-                        MessageType::GetTimeResponse,
-                        &api::GetTimeResponse {
-                            epoch_seconds: SystemTime::now()
-                                .duration_since(UNIX_EPOCH).map_err(|e| DeviceError::SystemTimeError(e))?
-                                .as_secs()
-                                .try_into().map_err(|e| DeviceError::SystemTimeIntCastError(e))?,
-                        },
-                    ).await?;
-                }
-                MessageType::SubscribeLogsResponse => {
-                    if let Some(log_tx) = &self.log_tx {
-                        let log = api::SubscribeLogsResponse::decode(msg)?;
-                        log_tx.send(Log {
-                            level: LogLevel::from_repr(log.level).ok_or(DeviceError::UnknownLogLevel(log.level))?,
-                            message: log.message.into(),
-                            send_failed: log.send_failed,
-                        }).await?;
-                    }
-                }
-                _ => {
-                    let update = self.process_state_update(&msg_type, msg)?;
-                    if let Some(send_update_tx) = &self.state_update_tx {
-                        send_update_tx.send(update).await?;
-                    }
-                },
+    /// Respond to protocol messages (ping, time, disconnect) and forward logs and state updates
+    pub(crate) async fn handle_message(&mut self, msg_type: MessageType, msg: BytesMut) -> Result<(), DeviceError> {
+        let Some(msg) = self.resolve_pending(&msg_type, msg) else {
+            return Ok(());
+        };
+        match msg_type {
+            MessageType::DisconnectRequest => {
+                self.send(
+                    MessageType::DisconnectResponse,
+                    &api::DisconnectResponse {},
+                ).await?;
+                self.on_connection_lost(Arc::new(DeviceError::DeviceRequestShutdown)).await;
+                return Err(DeviceError::DeviceRequestShutdown);
+            }
+            MessageType::PingRequest => {
+                self.send(MessageType::PingResponse, &api::PingResponse {}).await?;
+            }
+            MessageType::PingResponse => {
+                self.last_ping = Some(SystemTime::now());
+            }
+            MessageType::GetTimeRequest => {
+                self.send(
+                    MessageType::GetTimeResponse,
+                    &api::GetTimeResponse {
+                        epoch_seconds: SystemTime::now()
+                            .duration_since(UNIX_EPOCH).map_err(DeviceError::SystemTimeError)?
+                            .as_secs()
+                            .try_into().map_err(DeviceError::SystemTimeIntCastError)?,
+                    },
+                ).await?;
+            }
+            MessageType::HomeassistantServiceResponse => {
+                let call = api::HomeassistantServiceResponse::decode(msg)?;
+                self.ha_service_tx.send(call.into()).await;
+            }
+            MessageType::SubscribeHomeAssistantStateResponse => {
+                let req = api::SubscribeHomeAssistantStateResponse::decode(msg)?;
+                self.on_homeassistant_state_request(req.into()).await?;
+            }
+            MessageType::BluetoothLEAdvertisementResponse => {
+                let adv = api::BluetoothLeAdvertisementResponse::decode(msg)?;
+                self.on_bluetooth_advertisements(vec![adv.into()]).await?;
+            }
+            MessageType::BluetoothLERawAdvertisementsResponse => {
+                let res = api::BluetoothLeRawAdvertisementsResponse::decode(msg)?;
+                let advertisements = res.advertisements.into_iter()
+                    .map(|adv| BleAdvertisement::from_raw(adv.address, adv.address_type, adv.rssi, &adv.data))
+                    .collect();
+                self.on_bluetooth_advertisements(advertisements).await?;
+            }
+            MessageType::BluetoothConnectionsFreeResponse => {
+                let res = api::BluetoothConnectionsFreeResponse::decode(msg)?;
+                self.ble_slots_tx.send_replace(res.into());
             }
+            MessageType::BluetoothDeviceConnectionResponse |
+            MessageType::BluetoothGATTGetServicesResponse |
+            MessageType::BluetoothGATTGetServicesDoneResponse |
+            MessageType::BluetoothGATTReadResponse |
+            MessageType::BluetoothGATTWriteResponse |
+            MessageType::BluetoothGATTNotifyResponse |
+            MessageType::BluetoothGATTNotifyDataResponse |
+            MessageType::BluetoothGATTErrorResponse |
+            MessageType::BluetoothDevicePairingResponse |
+            MessageType::BluetoothDeviceUnpairingResponse |
+            MessageType::BluetoothDeviceClearCacheResponse => {
+                self.handle_gatt_message(msg_type, msg).await?;
+            }
+            MessageType::VoiceAssistantRequest => {
+                let req = api::VoiceAssistantRequest::decode(msg)?;
+                self.on_voice_assistant_request(req).await?;
+            }
+            MessageType::VoiceAssistantAudio => {
+                let audio = api::VoiceAssistantAudio::decode(msg)?;
+                self.on_voice_assistant_audio(audio).await;
+            }
+            // responses nobody waits for anymore (ex. after a timeout)
+            MessageType::DeviceInfoResponse | MessageType::DisconnectResponse
+            | MessageType::VoiceAssistantAnnounceFinished | MessageType::VoiceAssistantConfigurationResponse
+            | MessageType::NoiseEncryptionSetKeyResponse => {}
+            MessageType::CameraImageResponse => {
+                let res = api::CameraImageResponse::decode(msg)?;
+                self.on_camera_image(res).await;
+            }
+            MessageType::SubscribeLogsResponse => {
+                let log = api::SubscribeLogsResponse::decode(msg)?;
+                self.log_tx.send(Log {
+                    level: LogLevel::from_repr(log.level).ok_or(DeviceError::UnknownLogLevel(log.level))?,
+                    message: log.message.into(),
+                    send_failed: log.send_failed,
+                }).await;
+            }
+            _ => {
+                let update = self.process_state_update(&msg_type, msg)?;
+                self.state_update_tx.send(update).await;
+            },
         }
         Ok(())
     }
 
     pub async fn fetch_entities_and_services(&mut self) -> Result<(), DeviceError> {
         self.process_incoming().await?;
+        self.entities = EntityInfos::default();
+        self.entity_index_lut = EntityIndexLut::default();
+        self.services.clear();
         self.send(MessageType::ListEntitiesRequest, &api::ListEntitiesRequest {}).await?;
         loop {
-            let (msg_type, msg) = self.conn.receive_message(None).await?;
+            let (msg_type, msg) = self.receive_expected().await?;
 
             match msg_type {
                 MessageType::ListEntitiesServicesResponse => {
                     let res: UserService = api::ListEntitiesServicesResponse::decode(msg)?
-                        .try_into().map_err(|e| DeviceError::UserServiceParseError(e))?;
+                        .try_into().map_err(DeviceError::UserServiceParseError)?;
                     self.services.insert(res.key, res);
                 },
                 MessageType::ListEntitiesDoneResponse => break,
-                _ => self.save_entity(msg_type, msg)?
+                _ if Self::is_entity_info(&msg_type) => self.save_entity(msg_type, msg)?,
+                // ex. a state update or ping
+                _ => self.handle_other_message(msg_type, msg).await?,
             }
         }
         Ok(())
@@ -277,26 +748,116 @@ macro_rules! make_commands {
         impl ESPHomeDevice {
             $(
                 pub async fn [<$command:snake _command>](&mut self, req: &api::[<$command CommandRequest>]) -> Result<(), DeviceError> {
-                    self.send(MessageType::[<$command CommandRequest>], req).await?;
+                    if self.validate_commands {
+                        req.validate(self)?;
+                    }
+                    let mut req = req.clone();
+                    if let Some(version) = self.api_version {
+                        req.adapt(version);
+                    }
+                    self.send(MessageType::[<$command CommandRequest>], &req).await?;
                     Ok(())
                 }
 
                 /// Send a command to all entities
                 pub async fn [<$command:snake _command_global>](&mut self, req: &mut api::[<$command CommandRequest>]) -> Result<(), DeviceError> {
-                    for key in self.get_primary_light_keys() {
+                    for key in self.[<get_primary_ $command:snake _keys>]() {
                         req.key = key;
-                        self.send(MessageType::[<$command CommandRequest>], req).await?;
+                        self.[<$command:snake _command>](req).await?;
                     }
                     Ok(())
                 }
             )*
         }
+
+        impl DeviceHandle {
+            $(
+                pub async fn [<$command:snake _command>](&self, req: &api::[<$command CommandRequest>]) -> Result<(), DeviceError> {
+                    let req = req.clone();
+                    self.call(move |dev| Box::pin(async move { dev.[<$command:snake _command>](&req).await })).await?
+                }
+
+                /// Send a command to all entities
+                pub async fn [<$command:snake _command_global>](&self, req: &api::[<$command CommandRequest>]) -> Result<(), DeviceError> {
+                    let mut req = req.clone();
+                    self.call(move |dev| Box::pin(async move { dev.[<$command:snake _command_global>](&mut req).await })).await?
+                }
+            )*
+        }
     }}
 }
 
 make_commands! {
     Light, Cover, Fan, Switch, Climate,
-    Number, Siren, Lock, Button, MediaPlayer,
+    Number, Select, Siren, Lock, Button, MediaPlayer,
     AlarmControlPanel, Text, Date, Time, DateTime,
     Valve, Update
 }
+
+/// Wait outside the device task for a reply registered with `ESPHomeDevice::request`
+pub(crate) async fn reply<U: prost::Message + Default>(rx: oneshot::Receiver<BytesMut>) -> Result<U, DeviceError> {
+    let msg = rx.await.map_err(|_| DeviceError::NotConnected)?;
+    Ok(U::decode(msg)?)
+}
+
+/// `aa-bb-cc-dd-ee-ff` -> `AABBCCDDEEFF`
+fn normalize_mac(mac: &str) -> String {
+    mac.chars().filter(char::is_ascii_hexdigit).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::time::timeout;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn unknown_message_type() {
+        for noise in [false, true] {
+            let (mock, mut dev) = match noise {
+                true => connected_noise(mock_builder()).await,
+                false => connected_plain(mock_builder()).await,
+            };
+            let state = dev.watch_connection_state();
+
+            //a message type added by a newer ESPHome is skipped
+            mock.push_raw(200, &[1, 2, 3]);
+            timeout(TIMEOUT, dev.ping_wait()).await.unwrap().unwrap();
+            assert!(dev.connected);
+            assert!(!state.has_changed().unwrap());
+            assert_eq!(mock.clients(), 1);
+        }
+
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+        mock.push_raw(200, &[]);
+        timeout(TIMEOUT, handle.ping_wait()).await.unwrap().unwrap();
+        assert!(handle.is_connected().await.unwrap());
+        assert_eq!(mock.clients(), 1);
+    }
+
+    #[tokio::test]
+    async fn interleaved_responses() {
+        // the entity list is interleaved too
+        let (_mock, mut dev) = connected_plain(mock_builder()
+            .interleave(MessageType::SwitchStateResponse, &api::SwitchStateResponse { key: 2, state: true })
+            .interleave(MessageType::SwitchStateResponse, &api::SwitchStateResponse { key: 99, state: true })
+        ).await;
+        assert_eq!(dev.entities.switch.len(), 1);
+        let mut rx = timeout(TIMEOUT, dev.subscribe_states(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+
+        // the state updates (even one for an unknown entity) arrive first and are dispatched as usual
+        let info = timeout(TIMEOUT, dev.device_info()).await.unwrap().unwrap();
+        assert_eq!(info.name, "mock_bulb");
+        timeout(TIMEOUT, dev.ping_wait()).await.unwrap().unwrap();
+        let mut relay_updates = 0;
+        while let Some(Some(update)) = rx.recv().now_or_never() {
+            relay_updates += (update.entity_name == "relay") as usize;
+        }
+        assert_eq!(relay_updates, 2);
+
+        // recieve skips to the expected message
+        timeout(TIMEOUT, dev.ping()).await.unwrap().unwrap();
+        let _: api::PingResponse = timeout(TIMEOUT, dev.recieve(MessageType::PingResponse)).await.unwrap().unwrap();
+    }
+}
@@ -2,12 +2,15 @@ use std::collections::HashMap;
 
 use crate::error::DeviceError;
 use crate::api;
+use crate::compat::ApiCompat;
 use paste::paste;
 use prost::Message;
 use bytes::BytesMut;
 use crate::model::MessageType;
 use crate::device::ESPHomeDevice;
+use crate::handle::DeviceHandle;
 use strum_macros::Display;
+use tokio::sync::watch;
 
 pub const ENTITY_CATEGORY_NONE: i32 = 0;
 pub const ENTITY_CATEGORY_CONFIG: i32 = 1;
@@ -101,6 +104,11 @@ macro_rules! gen_entity_infos {
                         .collect()
                 })*
 
+                /// true for the `ListEntities*Response` of every entity type
+                pub(crate) fn is_entity_info(msg_type: &MessageType) -> bool {
+                    matches!(msg_type, $(MessageType::[<ListEntities $name Response>])|*)
+                }
+
                 pub(crate) fn save_entity(&mut self, msg_type: MessageType, msg: BytesMut) -> Result<(), DeviceError> {
                     match msg_type {
                         $(
@@ -127,7 +135,7 @@ macro_rules! gen_entity_infos {
 macro_rules! gen_entity_states {
     ($($name:ident),*) => {
         paste! {
-            #[derive(Debug)]
+            #[derive(Debug, Clone)]
             pub struct EntityStateUpdate {
                 pub entity_key: u32,
                 pub entity_index: usize,
@@ -135,21 +143,46 @@ macro_rules! gen_entity_states {
                 pub value: EntityStateUpdateValue
             }
 
-            #[derive(Debug)]
+            #[derive(Debug, Clone)]
             pub enum EntityStateUpdateValue {
                 $($name(api::[<$name StateResponse>]),)*
             }
 
+            /// Latest state of every entity (kept across reconnects)
+            #[derive(Default)]
+            pub struct EntityStates {
+                /// maps entity.key -> latest state (None until the device sends one)
+                $(pub(crate) [<$name:snake>]: HashMap<u32, watch::Sender<Option<api::[<$name StateResponse>]>>>,)*
+            }
+
             impl ESPHomeDevice {
+                /// get the latest state from entity.object_id
+                $(pub fn [<$name:snake _state>](&self, object_id: &str) -> Option<api::[<$name StateResponse>]> {
+                    let key = self.[<get_ $name:snake _key_from_name>](object_id)?;
+                    self.states.[<$name:snake>].get(&key)?.borrow().clone()
+                })*
+
+                /// watch the state of an entity from entity.object_id
+                $(pub fn [<watch_ $name:snake _state>](&mut self, object_id: &str) -> Option<watch::Receiver<Option<api::[<$name StateResponse>]>>> {
+                    let key = self.[<get_ $name:snake _key_from_name>](object_id)?;
+                    Some(self.states.[<$name:snake>].entry(key).or_insert_with(|| watch::Sender::new(None)).subscribe())
+                })*
+
                 pub(crate) fn process_state_update(&mut self, msg_type: &MessageType, msg: BytesMut) -> Result<EntityStateUpdate, DeviceError> {
                     match msg_type {
                         $(
                             MessageType::[<$name StateResponse>] => {
-                                let new_state = api::[<$name StateResponse>]::decode(msg)?;
+                                let mut new_state = api::[<$name StateResponse>]::decode(msg)?;
+                                if let Some(version) = self.api_version {
+                                    new_state.adapt(version);
+                                }
                                 let entity_key = new_state.key;
                                 let entity_index = *self.entity_index_lut.[<$name:snake _by_key>].get(&entity_key)
                                     .ok_or(DeviceError::StateUpdateForUnknownEntity(entity_key, EntityType::$name))?;
                                 let entity_name = self.entities.[<$name:snake>].get(entity_index).unwrap().object_id.clone();
+                                self.states.[<$name:snake>].entry(entity_key)
+                                    .or_insert_with(|| watch::Sender::new(None))
+                                    .send_replace(Some(new_state.clone()));
                                 let value = EntityStateUpdateValue::$name(new_state);
                                 Ok(EntityStateUpdate { entity_key, entity_index, entity_name, value })
                             }
@@ -158,6 +191,18 @@ macro_rules! gen_entity_states {
                     }
                 }
             }
+
+            impl DeviceHandle {
+                $(pub async fn [<$name:snake _state>](&self, object_id: &str) -> Result<Option<api::[<$name StateResponse>]>, DeviceError> {
+                    let object_id = object_id.to_string();
+                    self.call(move |dev| Box::pin(async move { dev.[<$name:snake _state>](&object_id) })).await
+                })*
+
+                $(pub async fn [<watch_ $name:snake _state>](&self, object_id: &str) -> Result<Option<watch::Receiver<Option<api::[<$name StateResponse>]>>>, DeviceError> {
+                    let object_id = object_id.to_string();
+                    self.call(move |dev| Box::pin(async move { dev.[<watch_ $name:snake _state>](&object_id) })).await
+                })*
+            }
         }
     }
 }
@@ -210,3 +255,28 @@ gen_entity_states!(
     DateTime,
     Update
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::subscription::OverflowPolicy;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn state_cache() {
+        let (mock, handle) = spawned_noise(mock_builder()).await;
+
+        let mut relay = handle.watch_switch_state("relay").await.unwrap().unwrap();
+        assert!(relay.borrow().is_none());
+        assert!(handle.watch_switch_state("missing").await.unwrap().is_none());
+        let mut light = handle.watch_light_state("rgbct_bulb").await.unwrap().unwrap();
+        let _sub = timeout(TIMEOUT, handle.subscribe_states(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+        timeout(TIMEOUT, light.wait_for(|s| s.is_some())).await.unwrap().unwrap();
+        assert!(handle.light_state("rgbct_bulb").await.unwrap().unwrap().state);
+
+        mock.push(MessageType::SwitchStateResponse, &api::SwitchStateResponse { key: 2, state: true });
+        timeout(TIMEOUT, relay.wait_for(|s| s.as_ref().is_some_and(|s| s.state))).await.unwrap().unwrap();
+        assert!(handle.switch_state("relay").await.unwrap().unwrap().state);
+    }
+}
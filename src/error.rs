@@ -1,13 +1,27 @@
+use std::{sync::Arc, time::Duration};
 use thiserror::Error;
-use tokio::sync::mpsc::error::SendError;
-use crate::{entity::{EntityStateUpdate, EntityType}, model::{Log, MessageType, UserServiceParseError}};
+use crate::{bluetooth::gatt::GattError, connection::options::TimeoutStage, entity::EntityType, model::{ApiVersion, MessageType, UserServiceParseError}, service::ServiceCallError, validate::InvalidCommand};
 
 #[derive(Error, Debug)]
 pub enum DeviceError {
     #[error("not connected")]
     NotConnected,
+    #[error("device task stopped")]
+    HandleClosed,
+    #[error("failed to reconnect after {0} attempts (last error `{1}`)")]
+    ReconnectFailed(u32, Box<DeviceError>),
+    #[error("connection lost `{0}`")]
+    ConnectionLost(Arc<DeviceError>),
+    #[error("nothing received from device for {0:?}")]
+    KeepaliveTimeout(Duration),
     #[error("device requested shutdown")]
     DeviceRequestShutdown,
+    #[error("incompatible API version {device} (client uses {client})")]
+    ApiVersionMismatch { client: ApiVersion, device: ApiVersion },
+    #[error("device refused the new noise_psk")]
+    NoisePskRejected,
+    #[error("{stage} timed out")]
+    Timeout { stage: TimeoutStage },
     #[error("invalid password")]
     InvalidPassword,
     #[error("connection error `{0}`")]
@@ -24,8 +38,16 @@ pub enum DeviceError {
     ProstEncodeError(prost::EncodeError),
     #[error("user service parse error `{0}`")]
     UserServiceParseError(UserServiceParseError),
+    #[error("service call error `{0}`")]
+    ServiceCall(ServiceCallError),
     #[error("state update for unknown entity (key=`{0}`, type=`{1}`)")]
     StateUpdateForUnknownEntity(u32, EntityType),
+    #[error("invalid command `{0}`")]
+    InvalidCommand(InvalidCommand),
+    #[error("bluetooth error `{0}`")]
+    Gatt(GattError),
+    #[error("no {0} entity named `{1}`")]
+    EntityNotFound(EntityType, String),
     #[error("unknown list entities reponse `{0}`")]
     UnknownListEntitiesResponse(MessageType),
     #[error("unknown entity category `{0}`")]
@@ -36,39 +58,54 @@ pub enum DeviceError {
     UnknownIncomingMessageType(MessageType),
     #[error("unknown log level `{0}`")]
     UnknownLogLevel(i32),
-    #[error("log send error `{0}`")]
-    LogChannelSendError(SendError<Log>),
-    #[error("entity state update send error `{0}`")]
-    EntityStateUpdateChannelSendError(SendError<EntityStateUpdate>),
+}
+
+impl DeviceError {
+    /// the connection can't be used anymore (as opposed to an error about a single message)
+    pub(crate) fn is_connection_error(&self) -> bool {
+        match self {
+            Self::ConnectionError(e) => e.is_connection_lost(),
+            _ => matches!(self, Self::NotConnected | Self::ConnectionLost(_) | Self::DeviceRequestShutdown | Self::KeepaliveTimeout(_)),
+        }
+    }
 }
 
 impl From<ConnectionError> for DeviceError {
     fn from(value: ConnectionError) -> Self {
-        Self::ConnectionError(value)
+        match value {
+            ConnectionError::Timeout(stage) => Self::Timeout { stage },
+            value => Self::ConnectionError(value),
+        }
     }
 }
 
-impl From<prost::DecodeError> for DeviceError {
-    fn from(value: prost::DecodeError) -> Self {
-        Self::ProstDecodeError(value)
+impl From<GattError> for DeviceError {
+    fn from(value: GattError) -> Self {
+        Self::Gatt(value)
     }
 }
 
-impl From<prost::EncodeError> for DeviceError {
-    fn from(value: prost::EncodeError) -> Self {
-        Self::ProstEncodeError(value)
+impl From<ServiceCallError> for DeviceError {
+    fn from(value: ServiceCallError) -> Self {
+        Self::ServiceCall(value)
     }
 }
 
-impl From<SendError<Log>> for DeviceError {
-    fn from(value: SendError<Log>) -> Self {
-        Self::LogChannelSendError(value)
+impl From<InvalidCommand> for DeviceError {
+    fn from(value: InvalidCommand) -> Self {
+        Self::InvalidCommand(value)
     }
 }
 
-impl From<SendError<EntityStateUpdate>> for DeviceError {
-    fn from(value: SendError<EntityStateUpdate>) -> Self {
-        Self::EntityStateUpdateChannelSendError(value)
+impl From<prost::DecodeError> for DeviceError {
+    fn from(value: prost::DecodeError) -> Self {
+        Self::ProstDecodeError(value)
+    }
+}
+
+impl From<prost::EncodeError> for DeviceError {
+    fn from(value: prost::EncodeError) -> Self {
+        Self::ProstEncodeError(value)
     }
 }
 
@@ -76,24 +113,60 @@ impl From<SendError<EntityStateUpdate>> for DeviceError {
 pub enum ConnectionError {
     #[error("not connected")]
     NotConnected,
+    #[error("stream was already used by a previous connection")]
+    StreamAlreadyUsed,
+    #[error("connection closed by device")]
+    ConnectionClosed,
     #[error("unknown message type `{0}`")]
     UnknownMessageType(u16),
+    #[error("message type `{0}` does not fit in a u16")]
+    MessageTypeTooLarge(u32),
+    #[error("frame too large ({0} bytes)")]
+    FrameTooLarge(usize),
+    #[error("frame too short ({0} bytes)")]
+    FrameTooShort(usize),
+    #[error("varu32 too long")]
+    Varu32TooLong,
     #[error("noise decrypt error `{0}`")]
     NoiseDecryptError(snow::error::Error),
     #[error("tcp io error `{0}`")]
     TcpIOError(std::io::Error),
+    #[deprecated(note = "no longer returned, an invalid noise_psk is `InvalidNoisePsk`")]
     #[error("base64 decode slice error `{0}` (noise_psk may be incorrectly sized)")]
     Base64DecodeSliceError(base64::DecodeSliceError),
+    #[error("noise_psk must be 32 bytes encoded as base64")]
+    InvalidNoisePsk,
     #[error("client wants unknown noise protocol `{0}`")]
     ClientWantsUnknownNoiseProtocol(u8),
     #[error("recieved message missing null terminator")]
     MessageMissingNullTerminator,
     #[error("handshake had wrong preamble `{0}`")]
     HandshakeHadWrongPreamble(u8),
+    #[error("connected to node `{got}` instead of `{expected}`")]
+    ServerNameMismatch { expected: String, got: String },
+    #[error("connected to {got} instead of {expected}")]
+    MacAddressMismatch { expected: String, got: String },
+    #[error("{0} timed out")]
+    Timeout(TimeoutStage),
+    #[error("device requires encryption (noise_psk)")]
+    EncryptionRequired,
+    #[error("device is not encrypted")]
+    NotEncrypted,
     #[error("frame had wrong preamble `{0}` (may have wrong Connection type)")]
     FrameHadWrongPreamble(u8),
 }
 
+impl ConnectionError {
+    /// the connection can't be used anymore (transport, IO and framing errors), as opposed to an error about a single message
+    pub(crate) fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            Self::NotConnected | Self::ConnectionClosed | Self::TcpIOError(_) | Self::NoiseDecryptError(_)
+                | Self::FrameTooLarge(_) | Self::FrameTooShort(_) | Self::Varu32TooLong | Self::MessageTypeTooLarge(_) | Self::FrameHadWrongPreamble(_)
+        )
+    }
+}
+
 impl From<std::io::Error> for ConnectionError {
     fn from(value: std::io::Error) -> Self {
         Self::TcpIOError(value)
@@ -106,6 +179,7 @@ impl From<snow::error::Error> for ConnectionError {
     }
 }
 
+#[allow(deprecated)]
 impl From<base64::DecodeSliceError> for ConnectionError {
     fn from(value: base64::DecodeSliceError) -> Self {
         Self::Base64DecodeSliceError(value)
@@ -0,0 +1,215 @@
+pub use futures::future::BoxFuture;
+use tokio::{sync::{mpsc::{self, Receiver, Sender}, oneshot, watch}, time::{sleep_until, Instant}};
+
+use crate::{
+    api, connection::options::{with_timeout, TimeoutStage}, device::{reply, ESPHomeDevice}, entity::EntityStateUpdate, error::DeviceError, model::{ConnectionState, HomeassistantServiceCall, Log, LogLevel}, subscription::{OverflowPolicy, Subscription}
+};
+
+type Call = Box<dyn for<'a> FnOnce(&'a mut ESPHomeDevice) -> BoxFuture<'a, ()> + Send>;
+
+/// max number of calls waiting for the background task
+const CALL_BUFFER_SIZE: usize = 32;
+
+/// Cheap, cloneable handle to an `ESPHomeDevice` owned by a background task.
+/// The task continuously reads from the socket (answering pings, time requests
+/// and forwarding state updates) and runs calls from every handle in order.
+/// If the device has a keepalive, the task pings it and detects dead connections.
+/// If the device has a reconnect policy, the task reconnects after the connection is lost.
+/// The task stops (and disconnects) when the last handle is dropped.
+#[derive(Clone)]
+pub struct DeviceHandle {
+    tx: Sender<Call>,
+}
+
+impl ESPHomeDevice {
+    /// Move this device into a background task and return a handle to it
+    pub fn spawn(self) -> DeviceHandle {
+        let (tx, rx) = mpsc::channel(CALL_BUFFER_SIZE);
+        tokio::spawn(run(self, rx));
+        DeviceHandle { tx }
+    }
+}
+
+async fn run(mut dev: ESPHomeDevice, mut rx: Receiver<Call>) {
+    // (failed attempts, when to try next) while reconnecting
+    let mut reconnect: Option<(u32, Instant)> = None;
+    let mut last_keepalive = Instant::now();
+    let mut last_camera_refresh = Instant::now();
+    loop {
+        let next_reconnect = reconnect.map(|(_, at)| at);
+        let next_keepalive = match &dev.keepalive {
+            Some(keepalive) => last_keepalive + keepalive.interval,
+            None => Instant::now(),
+        };
+        let next_camera_refresh = last_camera_refresh + dev.camera_refresh.unwrap_or_default();
+        tokio::select! {
+            call = rx.recv() => match call {
+                Some(call) => call(&mut dev).await,
+                None => break,
+            },
+            //connection errors are handled inside, others are for a single message (ex. unknown entity)
+            res = dev.receive_raw(), if dev.connected => {
+                if let Ok((msg_type, msg)) = res {
+                    let _ = dev.handle_message(msg_type, msg).await;
+                }
+            }
+            _ = sleep_until(next_keepalive), if dev.connected && dev.keepalive.is_some() => {
+                last_keepalive = Instant::now();
+                let _ = dev.check_keepalive().await;
+            }
+            _ = sleep_until(next_camera_refresh), if dev.connected && dev.camera_refresh.is_some() => {
+                last_camera_refresh = Instant::now();
+                let _ = dev.refresh_camera_stream().await;
+            }
+            _ = sleep_until(next_reconnect.unwrap_or_else(Instant::now)), if next_reconnect.is_some() => {
+                let (attempts, _) = reconnect.take().unwrap();
+                if dev.connect().await.is_err() {
+                    let policy = dev.reconnect_policy.clone().unwrap_or_default();
+                    let attempts = attempts + 1;
+                    if !policy.exhausted(attempts) {
+                        reconnect = Some((attempts, Instant::now() + policy.delay(attempts)));
+                    }
+                    else {
+                        dev.connection_lost = false;
+                    }
+                }
+            }
+        }
+
+        if dev.connection_lost && reconnect.is_none() && dev.reconnect_policy.is_some() {
+            reconnect = Some((0, Instant::now()));
+        }
+        else if !dev.connection_lost {
+            reconnect = None;
+        }
+    }
+    let _ = dev.force_disconnect().await;
+}
+
+impl DeviceHandle {
+    /// Run `f` with the device inside the background task and return its result.
+    /// Ex. `handle.call(|dev| Box::pin(async move { dev.get_light_key_from_name("rgbct_bulb") })).await?`
+    pub async fn call<R, F>(&self, f: F) -> Result<R, DeviceError>
+    where
+        F: for<'a> FnOnce(&'a mut ESPHomeDevice) -> BoxFuture<'a, R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (res_tx, res_rx) = oneshot::channel();
+        let call: Call = Box::new(move |dev: &mut ESPHomeDevice| -> BoxFuture<'_, ()> {
+            Box::pin(async move {
+                let _ = res_tx.send(f(dev).await);
+            })
+        });
+        self.tx.send(call).await.map_err(|_| DeviceError::HandleClosed)?;
+        res_rx.await.map_err(|_| DeviceError::HandleClosed)
+    }
+
+    pub async fn connect(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.connect())).await?
+    }
+
+    pub async fn is_connected(&self) -> Result<bool, DeviceError> {
+        self.call(|dev| Box::pin(async move { dev.connected })).await
+    }
+
+    /// Watch the connection state (including why the connection was lost)
+    pub async fn watch_connection_state(&self) -> Result<watch::Receiver<ConnectionState>, DeviceError> {
+        self.call(|dev| Box::pin(async move { dev.watch_connection_state() })).await
+    }
+
+    /// Ping without waiting for response
+    pub async fn ping(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.ping())).await?
+    }
+
+    /// Ping and wait for response
+    pub async fn ping_wait(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.ping_wait())).await?
+    }
+
+    /// Send disconnect request to device, wait for response, then disconnect socket
+    pub async fn disconnect(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.disconnect())).await?
+    }
+
+    /// Disconnect socket (without sending disconnect request to device)
+    pub async fn force_disconnect(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.force_disconnect())).await?
+    }
+
+    pub async fn device_info(&self) -> Result<api::DeviceInfoResponse, DeviceError> {
+        self.call(|dev| Box::pin(dev.device_info())).await?
+    }
+
+    /// Request device to send state updates.
+    /// Returns a stream of state updates, see `ESPHomeDevice::subscribe_states`.
+    /// Note: with `OverflowPolicy::Block` a slow subscriber stalls the whole device (including other subscribers)
+    pub async fn subscribe_states(&self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<EntityStateUpdate>, DeviceError> {
+        self.call(move |dev| Box::pin(dev.subscribe_states(buffer_size, policy))).await?
+    }
+
+    /// Request device to send logs.
+    /// Returns a stream of logs, see `ESPHomeDevice::subscribe_logs`
+    pub async fn subscribe_logs(&self, level: LogLevel, dump_config: bool, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<Log>, DeviceError> {
+        self.call(move |dev| Box::pin(dev.subscribe_logs(level, dump_config, buffer_size, policy))).await?
+    }
+
+    /// Request device to send Home Assistant service calls and events, see `ESPHomeDevice::subscribe_homeassistant_services`
+    pub async fn subscribe_homeassistant_services(&self, buffer_size: usize, policy: OverflowPolicy) -> Result<Subscription<HomeassistantServiceCall>, DeviceError> {
+        self.call(move |dev| Box::pin(dev.subscribe_homeassistant_services(buffer_size, policy))).await?
+    }
+
+    /// Change the device's noise PSK, see `ESPHomeDevice::set_noise_psk`
+    pub async fn set_noise_psk(&self, noise_psk: &str) -> Result<(), DeviceError> {
+        let noise_psk = noise_psk.to_string();
+        let (rx, request_timeout) = {
+            let noise_psk = noise_psk.clone();
+            self.call(move |dev| Box::pin(async move {
+                Ok::<_, DeviceError>((dev.request_noise_psk_change(&noise_psk).await?, dev.request_timeout()))
+            })).await??
+        };
+        let res: api::NoiseEncryptionSetKeyResponse = with_timeout(request_timeout, TimeoutStage::Request, reply(rx)).await?;
+        self.call(move |dev| Box::pin(async move { dev.on_noise_psk_changed(&noise_psk, res.success) })).await?
+    }
+
+    /// The noise PSK the device accepted for the current connection (None for plaintext)
+    pub async fn active_noise_psk(&self) -> Result<Option<String>, DeviceError> {
+        self.call(|dev| Box::pin(async move { dev.active_noise_psk() })).await
+    }
+
+    pub async fn execute_service(&self, req: &api::ExecuteServiceRequest) -> Result<(), DeviceError> {
+        let req = req.clone();
+        self.call(move |dev| Box::pin(async move { dev.execute_service(&req).await })).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::entity::EntityStateUpdateValue;
+    use crate::model::MessageType;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn handle_states_and_commands() {
+        let (mock, handle) = spawned_noise(mock_builder()).await;
+
+        let mut rx = timeout(TIMEOUT, handle.subscribe_states(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+        let update = timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        assert_eq!(update.entity_name, "rgbct_bulb");
+        assert!(matches!(update.value, EntityStateUpdateValue::Light(ref s) if s.state));
+
+        mock.push(MessageType::SwitchStateResponse, &api::SwitchStateResponse { key: 2, state: true });
+        let update = timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        assert_eq!(update.entity_name, "relay");
+
+        let h = handle.clone();
+        tokio::spawn(async move {
+            h.light_command(&api::LightCommandRequest { key: 1, has_state: true, state: false, ..Default::default() }).await
+        }).await.unwrap().unwrap();
+        let cmd: api::LightCommandRequest = timeout(TIMEOUT, mock.wait_for(MessageType::LightCommandRequest)).await.unwrap().unwrap();
+        assert_eq!(cmd.key, 1);
+        assert!(cmd.has_state && !cmd.state);
+    }
+}
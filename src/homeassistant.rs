@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use crate::{api, device::ESPHomeDevice, error::DeviceError, handle::DeviceHandle, model::MessageType};
+
+/// Supplies Home Assistant states to devices using `homeassistant` sensors
+/// (implemented for `Fn(entity_id, attribute) -> Option<String>`)
+pub trait HomeassistantStateProvider: Send + Sync {
+    /// current state of `entity_id` (or its `attribute` if not empty), None if unknown
+    fn state(&self, entity_id: &str, attribute: &str) -> Option<String>;
+}
+
+impl<F: Fn(&str, &str) -> Option<String> + Send + Sync> HomeassistantStateProvider for F {
+    fn state(&self, entity_id: &str, attribute: &str) -> Option<String> {
+        self(entity_id, attribute)
+    }
+}
+
+/// A state the device asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HomeassistantStateRequest {
+    pub entity_id: String,
+    /// empty for the state itself
+    pub attribute: String,
+    /// only wants the current value, not updates
+    pub once: bool,
+}
+
+impl From<api::SubscribeHomeAssistantStateResponse> for HomeassistantStateRequest {
+    fn from(value: api::SubscribeHomeAssistantStateResponse) -> Self {
+        Self { entity_id: value.entity_id, attribute: value.attribute, once: value.once }
+    }
+}
+
+impl ESPHomeDevice {
+    /// Export states from `provider` to the device. The device requests the states it needs
+    /// (answered from `provider`), further changes are pushed with `update_homeassistant_state`.
+    pub async fn export_homeassistant_states(&mut self, provider: Arc<dyn HomeassistantStateProvider>) -> Result<(), DeviceError> {
+        self.ha_state_provider = Some(provider);
+        self.ha_state_requests.clear();
+        self.send(MessageType::SubscribeHomeAssistantStatesRequest, &api::SubscribeHomeAssistantStatesRequest {}).await
+    }
+
+    /// States the device currently wants
+    pub fn requested_homeassistant_states(&self) -> &[HomeassistantStateRequest] {
+        &self.ha_state_requests
+    }
+
+    /// Push a changed state to the device. Returns false if the device didn't ask for it.
+    pub async fn update_homeassistant_state(&mut self, entity_id: &str, attribute: &str, state: &str) -> Result<bool, DeviceError> {
+        let Some(index) = self.ha_state_requests.iter()
+            .position(|r| r.entity_id == entity_id && r.attribute == attribute) else {
+            return Ok(false)
+        };
+        if self.ha_state_requests[index].once {
+            self.ha_state_requests.remove(index);
+        }
+        self.send_homeassistant_state(entity_id, attribute, state).await?;
+        Ok(true)
+    }
+
+    async fn send_homeassistant_state(&mut self, entity_id: &str, attribute: &str, state: &str) -> Result<(), DeviceError> {
+        self.send(MessageType::HomeAssistantStateResponse, &api::HomeAssistantStateResponse {
+            entity_id: entity_id.to_string(),
+            state: state.to_string(),
+            attribute: attribute.to_string(),
+        }).await
+    }
+
+    /// track the request, answering from the provider if it knows the state
+    pub(crate) async fn on_homeassistant_state_request(&mut self, req: HomeassistantStateRequest) -> Result<(), DeviceError> {
+        let state = self.ha_state_provider.as_ref().and_then(|p| p.state(&req.entity_id, &req.attribute));
+        if let Some(state) = &state {
+            self.send_homeassistant_state(&req.entity_id, &req.attribute, state).await?;
+        }
+        let done = req.once && state.is_some();
+        let existing = self.ha_state_requests.iter()
+            .position(|r| r.entity_id == req.entity_id && r.attribute == req.attribute);
+        match existing {
+            Some(index) if done && self.ha_state_requests[index].once => {
+                self.ha_state_requests.remove(index);
+            }
+            // a subscription stays a subscription even if also requested once
+            Some(index) => self.ha_state_requests[index].once &= req.once,
+            None if !done => self.ha_state_requests.push(req),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl DeviceHandle {
+    /// see `ESPHomeDevice::export_homeassistant_states`
+    pub async fn export_homeassistant_states(&self, provider: impl HomeassistantStateProvider + 'static) -> Result<(), DeviceError> {
+        let provider: Arc<dyn HomeassistantStateProvider> = Arc::new(provider);
+        self.call(move |dev| Box::pin(dev.export_homeassistant_states(provider))).await?
+    }
+
+    pub async fn requested_homeassistant_states(&self) -> Result<Vec<HomeassistantStateRequest>, DeviceError> {
+        self.call(|dev| Box::pin(async move { dev.requested_homeassistant_states().to_vec() })).await
+    }
+
+    /// Push a changed state to the device. Returns false if the device didn't ask for it.
+    pub async fn update_homeassistant_state(&self, entity_id: &str, attribute: &str, state: &str) -> Result<bool, DeviceError> {
+        let (entity_id, attribute, state) = (entity_id.to_string(), attribute.to_string(), state.to_string());
+        self.call(move |dev| Box::pin(async move {
+            dev.update_homeassistant_state(&entity_id, &attribute, &state).await
+        })).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use crate::model::HomeassistantCallType;
+    use crate::subscription::OverflowPolicy;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn homeassistant_services() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let mut calls = timeout(TIMEOUT, handle.subscribe_homeassistant_services(8, OverflowPolicy::Block)).await.unwrap().unwrap();
+        let _: api::SubscribeHomeassistantServicesRequest = timeout(TIMEOUT, mock.wait_for(MessageType::SubscribeHomeassistantServicesRequest)).await.unwrap().unwrap();
+        let map = |key: &str, value: &str| api::HomeassistantServiceMap { key: key.to_string(), value: value.to_string() };
+        mock.push(MessageType::HomeassistantServiceResponse, &api::HomeassistantServiceResponse {
+            service: "light.turn_on".to_string(),
+            data: vec![map("entity_id", "light.porch")],
+            data_template: vec![map("brightness", "{{ level }}")],
+            variables: vec![map("level", "128")],
+            is_event: false,
+        });
+        mock.push(MessageType::HomeassistantServiceResponse, &api::HomeassistantServiceResponse {
+            service: "esphome.button_pressed".to_string(),
+            is_event: true,
+            ..Default::default()
+        });
+
+        let call = timeout(TIMEOUT, calls.recv()).await.unwrap().unwrap();
+        assert_eq!((call.typ, call.service.as_str()), (HomeassistantCallType::Service, "light.turn_on"));
+        assert_eq!(call.data["entity_id"], "light.porch");
+        assert_eq!(call.data_template["brightness"], "{{ level }}");
+        assert_eq!(call.variables["level"], "128");
+        let event = timeout(TIMEOUT, calls.recv()).await.unwrap().unwrap();
+        assert_eq!((event.typ, event.service.as_str()), (HomeassistantCallType::Event, "esphome.button_pressed"));
+        assert!(event.data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn homeassistant_state_export() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let provider = |entity_id: &str, attribute: &str| match (entity_id, attribute) {
+            ("sensor.outside", "") => Some("12.5".to_string()),
+            ("sun.sun", "elevation") => Some("30".to_string()),
+            _ => None,
+        };
+        timeout(TIMEOUT, handle.export_homeassistant_states(provider)).await.unwrap().unwrap();
+        let _: api::SubscribeHomeAssistantStatesRequest = timeout(TIMEOUT, mock.wait_for(MessageType::SubscribeHomeAssistantStatesRequest)).await.unwrap().unwrap();
+
+        let request = |entity_id: &str, attribute: &str, once| api::SubscribeHomeAssistantStateResponse {
+            entity_id: entity_id.to_string(), attribute: attribute.to_string(), once
+        };
+        mock.push(MessageType::SubscribeHomeAssistantStateResponse, &request("sensor.outside", "", false));
+        let state: api::HomeAssistantStateResponse = timeout(TIMEOUT, mock.wait_for(MessageType::HomeAssistantStateResponse)).await.unwrap().unwrap();
+        assert_eq!((state.entity_id.as_str(), state.state.as_str()), ("sensor.outside", "12.5"));
+        mock.push(MessageType::SubscribeHomeAssistantStateResponse, &request("sun.sun", "elevation", true));
+        let state: api::HomeAssistantStateResponse = timeout(TIMEOUT, mock.wait_for(MessageType::HomeAssistantStateResponse)).await.unwrap().unwrap();
+        assert_eq!((state.attribute.as_str(), state.state.as_str()), ("elevation", "30"));
+        mock.push(MessageType::SubscribeHomeAssistantStateResponse, &request("sensor.unknown", "", true));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let requested = handle.requested_homeassistant_states().await.unwrap();
+        assert_eq!(requested.iter().map(|r| r.entity_id.as_str()).collect::<Vec<_>>(), ["sensor.outside", "sensor.unknown"]);
+        assert!(!handle.update_homeassistant_state("sun.sun", "elevation", "31").await.unwrap());
+        assert!(handle.update_homeassistant_state("sensor.outside", "", "13").await.unwrap());
+        let state: api::HomeAssistantStateResponse = timeout(TIMEOUT, mock.wait_for(MessageType::HomeAssistantStateResponse)).await.unwrap().unwrap();
+        assert_eq!(state.state, "13");
+        assert!(handle.update_homeassistant_state("sensor.unknown", "", "on").await.unwrap());
+        assert_eq!(handle.requested_homeassistant_states().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+/// Periodically ping the device and treat the connection as dead if nothing
+/// (ping response or any other message) was received within `timeout`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(20),
+            timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::connection::options::ConnectionOptions;
+    use crate::device::ESPHomeDevice;
+    use crate::error::DeviceError;
+    use crate::model::ConnectionState;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn keepalive_timeout() {
+        let mock = mock_builder().start().await.unwrap();
+        let short = Some(Duration::from_millis(200));
+        let keepalive = Keepalive { interval: Duration::from_millis(50), timeout: Duration::from_millis(300) };
+        let handle = ESPHomeDevice::new_plain(mock.addr(), String::new())
+            .with_keepalive(keepalive.clone())
+            .with_connection_options(ConnectionOptions { read_timeout: short, request_timeout: short, ..Default::default() })
+            .with_reconnect_policy(ReconnectPolicy { initial_delay: Duration::from_millis(10), max_delay: Duration::from_millis(50), ..Default::default() })
+            .spawn();
+        timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+        let mut state = handle.watch_connection_state().await.unwrap();
+
+        // still answering pings: stays connected well past the keepalive timeout
+        tokio::time::sleep(keepalive.timeout * 2).await;
+        assert!(matches!(*state.borrow(), ConnectionState::Connected));
+
+        mock.set_unresponsive(true);
+        let lost = timeout(TIMEOUT, state.wait_for(|s| matches!(s, ConnectionState::Lost(_)))).await.unwrap().unwrap().clone();
+        assert!(matches!(lost, ConnectionState::Lost(ref e) if matches!(**e, DeviceError::KeepaliveTimeout(since) if since >= keepalive.timeout)));
+
+        // the reconnect policy takes over once the device answers again
+        mock.set_unresponsive(false);
+        timeout(TIMEOUT, state.wait_for(|s| matches!(s, ConnectionState::Connected))).await.unwrap().unwrap();
+        timeout(TIMEOUT, handle.ping_wait()).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use base64::prelude::*;
+use bytes::{Buf, BufMut, BytesMut};
+use prost::Message;
+use snow::{HandshakeState, TransportState};
+use std::{collections::VecDeque, io, net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+use futures::{SinkExt, StreamExt};
+use tokio::{io::{AsyncWriteExt, DuplexStream}, net::TcpListener, sync::{mpsc, Notify}, task::JoinHandle};
+use tokio_util::codec::Framed;
+
+use crate::{
+    api, connection::{codec::{NoiseFrameCodec, PlainFrameCodec, NOISE_MAX_FRAME_LEN}, noise::{decode_noise_psk, NOISE_PARAMS, NOISE_PROLOGUE, NOISE_PSK_LEN}, transport::BoxedStream, util::parse_message_type},
+    error::ConnectionError, model::MessageType
+};
+
+/// In-process fake ESPHome device (API server) listening on loopback.
+/// Serves scripted entities and states, answers the protocol messages
+/// (hello, connect, ping, device info, ...) and records everything else it receives.
+/// The server stops when this is dropped.
+pub struct MockDevice {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct MockDeviceBuilder {
+    noise_psk: Option<String>,
+    password: String,
+    name: String,
+    device_info: api::DeviceInfoResponse,
+    api_version: Option<(u32, u32)>,
+    entities: Vec<(MessageType, BytesMut)>,
+    states: Vec<(MessageType, BytesMut)>,
+    interleaved: Vec<(MessageType, BytesMut)>,
+}
+
+enum Outgoing {
+    Message(u16, BytesMut),
+    Close,
+}
+
+struct Shared {
+    config: MockDeviceBuilder,
+    /// current noise key (can be changed with NoiseEncryptionSetKeyRequest)
+    noise_psk: Mutex<Option<String>>,
+    received: Mutex<VecDeque<(MessageType, BytesMut)>>,
+    received_notify: Notify,
+    clients: Mutex<Vec<mpsc::UnboundedSender<Outgoing>>>,
+    /// ignore every request, keeping the connections open (a hung device)
+    unresponsive: AtomicBool,
+}
+
+impl MockDeviceBuilder {
+    /// Use the noise protocol with `noise_psk` (base64) instead of plaintext
+    pub fn noise_psk(mut self, noise_psk: &str) -> Self {
+        self.noise_psk = Some(noise_psk.to_string());
+        self
+    }
+
+    /// Password required by ConnectRequest (plaintext only)
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = password.to_string();
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// API version sent in HelloResponse (default 1.10)
+    pub fn api_version(mut self, major: u32, minor: u32) -> Self {
+        self.api_version = Some((major, minor));
+        self
+    }
+
+    pub fn device_info(mut self, device_info: api::DeviceInfoResponse) -> Self {
+        self.device_info = device_info;
+        self
+    }
+
+    /// Add an entity (ex. `MessageType::ListEntitiesLightResponse`) sent on ListEntitiesRequest
+    pub fn entity(mut self, msg_type: MessageType, msg: &impl Message) -> Self {
+        self.entities.push((msg_type, encode(msg)));
+        self
+    }
+
+    /// Add a state (ex. `MessageType::LightStateResponse`) sent on SubscribeStatesRequest
+    pub fn state(mut self, msg_type: MessageType, msg: &impl Message) -> Self {
+        self.states.push((msg_type, encode(msg)));
+        self
+    }
+
+    /// Send this (ex. a state update) right before answering every ping and device info request (and ending the entity list)
+    pub fn interleave(mut self, msg_type: MessageType, msg: &impl Message) -> Self {
+        self.interleaved.push((msg_type, encode(msg)));
+        self
+    }
+
+    pub async fn start(mut self) -> io::Result<MockDevice> {
+        if self.name.is_empty() {
+            self.name = "mock".to_string();
+        }
+        if self.device_info.name.is_empty() {
+            self.device_info.name = self.name.clone();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            noise_psk: Mutex::new(self.noise_psk.clone()),
+            config: self,
+            received: Mutex::new(VecDeque::new()),
+            received_notify: Notify::new(),
+            clients: Mutex::new(Vec::new()),
+            unresponsive: AtomicBool::new(false),
+        });
+        let task = tokio::spawn(accept(listener, shared.clone()));
+        Ok(MockDevice { addr, shared, task })
+    }
+}
+
+impl MockDevice {
+    pub fn builder() -> MockDeviceBuilder {
+        MockDeviceBuilder::default()
+    }
+
+    /// Address to connect to (ex. "127.0.0.1:41235")
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Open an in-memory stream to the mock (instead of connecting over TCP)
+    pub fn duplex(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        add_client(Box::new(server), &self.shared);
+        client
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        let mut clients = self.shared.clients.lock().unwrap();
+        clients.retain(|c| !c.is_closed());
+        clients.len()
+    }
+
+    /// Send a message (ex. a state update) to every connected client
+    pub fn push(&self, msg_type: MessageType, msg: &impl Message) {
+        self.push_raw(msg_type as u16, &encode(msg));
+    }
+
+    /// Send a message with any type number (ex. one the client doesn't know) to every connected client
+    pub fn push_raw(&self, msg_type: u16, msg: &[u8]) {
+        for client in self.shared.clients.lock().unwrap().iter() {
+            let _ = client.send(Outgoing::Message(msg_type, BytesMut::from(msg)));
+        }
+    }
+
+    /// Stop (or resume) answering anything, like a hung device whose connections stay open
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.shared.unresponsive.store(unresponsive, Ordering::Relaxed);
+    }
+
+    /// Close every client connection (without a DisconnectRequest)
+    pub fn disconnect_clients(&self) {
+        for client in self.shared.clients.lock().unwrap().drain(..) {
+            let _ = client.send(Outgoing::Close);
+        }
+    }
+
+    /// Every message received (and not taken by `wait_for`) that the mock doesn't handle itself
+    pub fn received(&self) -> Vec<(MessageType, BytesMut)> {
+        self.shared.received.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Wait for a message of `msg_type`, then remove it from `received` and return it
+    pub async fn wait_for<T: Message + Default>(&self, msg_type: MessageType) -> Result<T, prost::DecodeError> {
+        loop {
+            let notified = self.shared.received_notify.notified();
+            {
+                let mut received = self.shared.received.lock().unwrap();
+                if let Some(i) = received.iter().position(|(t, _)| *t == msg_type) {
+                    let (_, msg) = received.remove(i).unwrap();
+                    return T::decode(msg);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for MockDevice {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.disconnect_clients();
+    }
+}
+
+fn encode(msg: &impl Message) -> BytesMut {
+    let mut bytes = BytesMut::with_capacity(msg.encoded_len());
+    msg.encode(&mut bytes).unwrap();
+    bytes
+}
+
+async fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        add_client(Box::new(stream), &shared);
+    }
+}
+
+fn add_client(stream: BoxedStream, shared: &Arc<Shared>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    shared.clients.lock().unwrap().push(tx);
+    tokio::spawn(serve(stream, shared.clone(), rx));
+}
+
+enum Client {
+    Plain(Framed<BoxedStream, PlainFrameCodec>),
+    Noise(Framed<BoxedStream, NoiseFrameCodec>, Box<TransportState>),
+}
+
+async fn serve(stream: BoxedStream, shared: Arc<Shared>, mut rx: mpsc::UnboundedReceiver<Outgoing>) {
+    let noise_psk = shared.noise_psk.lock().unwrap().clone();
+    let mut client = match noise_psk {
+        Some(psk) => {
+            let mut stream = Framed::new(stream, NoiseFrameCodec);
+            match handshake(&mut stream, &psk, &shared.config.name).await {
+                Ok(noise) => Client::Noise(stream, Box::new(noise)),
+                Err(_) => return,
+            }
+        }
+        None => Client::Plain(Framed::new(stream, PlainFrameCodec)),
+    };
+
+    loop {
+        tokio::select! {
+            res = client.receive() => match res {
+                Ok((msg_type, msg)) => {
+                    if client.handle(&shared, msg_type, msg).await.is_err() {
+                        return;
+                    }
+                }
+                Err(ConnectionError::FrameHadWrongPreamble(_)) => {
+                    // like ESPHome, answer a noise client with the plaintext preamble
+                    if let Client::Plain(stream) = &mut client {
+                        let _ = stream.get_mut().write_all(b"\x00Bad indicator byte").await;
+                    }
+                    return;
+                }
+                Err(_) => return,
+            },
+            out = rx.recv() => match out {
+                Some(Outgoing::Message(msg_type, msg)) => {
+                    if client.send(msg_type, &msg).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Outgoing::Close) | None => return,
+            }
+        }
+    }
+}
+
+/// Responder side of the noise handshake
+async fn handshake(stream: &mut Framed<BoxedStream, NoiseFrameCodec>, psk: &str, name: &str) -> Result<TransportState, ConnectionError> {
+    let key = decode_noise_psk(psk)?;
+    let mut noise: HandshakeState = snow::Builder::new(NOISE_PARAMS.parse()?)
+        .psk(0, &key)
+        .prologue(NOISE_PROLOGUE)
+        .build_responder()?;
+
+    //client hello (empty), answered with the server hello: chosen protocol, name, mac
+    if let Err(e) = read_frame(stream).await {
+        // like ESPHome, answer a plaintext client with a noise frame
+        if matches!(e, ConnectionError::FrameHadWrongPreamble(_)) {
+            stream.send(&b"\x01Bad indicator byte"[..]).await?;
+        }
+        return Err(e);
+    }
+    let mut hello = BytesMut::new();
+    hello.put_u8(0x01);
+    hello.extend_from_slice(name.as_bytes());
+    hello.put_u8(0x00);
+    hello.put_u8(0x00);
+    stream.send(&hello[..]).await?;
+
+    //the clients handshake, rejected like ESPHome does if the key doesn't match
+    let frame = read_frame(stream).await?;
+    if frame.first() != Some(&0x00) {
+        return Err(ConnectionError::HandshakeHadWrongPreamble(frame.first().copied().unwrap_or(0)));
+    }
+    if let Err(e) = noise.read_message(&frame[1..], &mut []) {
+        stream.send(&b"\x01Handshake MAC failure"[..]).await?;
+        return Err(e.into());
+    }
+
+    let mut handshake = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
+    let len = noise.write_message(&[], &mut handshake[1..])?;
+    stream.send(&handshake[..len + 1]).await?;
+
+    Ok(noise.into_transport_mode()?)
+}
+
+async fn read_frame(stream: &mut Framed<BoxedStream, NoiseFrameCodec>) -> Result<BytesMut, ConnectionError> {
+    stream.next().await.unwrap_or(Err(ConnectionError::ConnectionClosed))
+}
+
+impl Client {
+    /// Read the next message (cancel safe)
+    async fn receive(&mut self) -> Result<(MessageType, BytesMut), ConnectionError> {
+        match self {
+            Client::Plain(stream) => {
+                let (msg_type_num, msg) = stream.next().await.unwrap_or(Err(ConnectionError::ConnectionClosed))?;
+                Ok((parse_message_type(msg_type_num)?, msg))
+            }
+            Client::Noise(stream, noise) => {
+                let frame = read_frame(stream).await?;
+                let mut msg = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
+                let len = noise.read_message(&frame, &mut msg)?;
+                msg.truncate(len);
+                if msg.len() < 4 {
+                    return Err(ConnectionError::FrameTooShort(msg.len()));
+                }
+                let msg_type = parse_message_type(u16::from_be_bytes([msg[0], msg[1]]))?;
+                msg.advance(4);
+                Ok((msg_type, msg))
+            }
+        }
+    }
+
+    async fn send(&mut self, msg_type: u16, msg: &[u8]) -> Result<(), ConnectionError> {
+        match self {
+            Client::Plain(stream) => stream.send((msg_type, msg)).await,
+            Client::Noise(stream, noise) => {
+                let mut frame = BytesMut::with_capacity(msg.len() + 4);
+                frame.put_u16(msg_type);
+                frame.put_u16(msg.len() as u16);
+                frame.extend_from_slice(msg);
+                let mut eframe = BytesMut::zeroed(NOISE_MAX_FRAME_LEN);
+                let len = noise.write_message(&frame, &mut eframe)?;
+                stream.send(&eframe[..len]).await
+            }
+        }
+    }
+
+    async fn send_msg(&mut self, msg_type: MessageType, msg: &impl Message) -> Result<(), ConnectionError> {
+        self.send(msg_type as u16, &encode(msg)).await
+    }
+
+    async fn send_interleaved(&mut self, config: &MockDeviceBuilder) -> Result<(), ConnectionError> {
+        for (msg_type, msg) in &config.interleaved {
+            self.send(msg_type.clone() as u16, msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Answer protocol messages, record everything else
+    async fn handle(&mut self, shared: &Shared, msg_type: MessageType, msg: BytesMut) -> Result<(), ConnectionError> {
+        let config = &shared.config;
+        if shared.unresponsive.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        match msg_type {
+            MessageType::HelloRequest => {
+                let (api_version_major, api_version_minor) = config.api_version.unwrap_or((1, 10));
+                self.send_msg(MessageType::HelloResponse, &api::HelloResponse {
+                    api_version_major,
+                    api_version_minor,
+                    server_info: "esphomebridge-rs mock".to_string(),
+                    name: config.name.clone(),
+                }).await?;
+            }
+            MessageType::ConnectRequest => {
+                let req = api::ConnectRequest::decode(msg).unwrap_or_default();
+                self.send_msg(MessageType::ConnectResponse, &api::ConnectResponse {
+                    invalid_password: req.password != config.password,
+                }).await?;
+            }
+            MessageType::DisconnectRequest => {
+                self.send_msg(MessageType::DisconnectResponse, &api::DisconnectResponse {}).await?;
+                return Err(ConnectionError::ConnectionClosed);
+            }
+            MessageType::PingRequest => {
+                self.send_interleaved(config).await?;
+                self.send_msg(MessageType::PingResponse, &api::PingResponse {}).await?;
+            }
+            MessageType::DeviceInfoRequest => {
+                self.send_interleaved(config).await?;
+                self.send_msg(MessageType::DeviceInfoResponse, &config.device_info).await?;
+            }
+            MessageType::ListEntitiesRequest => {
+                for (msg_type, msg) in &config.entities {
+                    self.send(msg_type.clone() as u16, msg).await?;
+                }
+                self.send_interleaved(config).await?;
+                self.send_msg(MessageType::ListEntitiesDoneResponse, &api::ListEntitiesDoneResponse {}).await?;
+            }
+            MessageType::SubscribeStatesRequest => {
+                for (msg_type, msg) in &config.states {
+                    self.send(msg_type.clone() as u16, msg).await?;
+                }
+            }
+            MessageType::NoiseEncryptionSetKeyRequest => {
+                let req = api::NoiseEncryptionSetKeyRequest::decode(msg).unwrap_or_default();
+                let success = {
+                    let mut noise_psk = shared.noise_psk.lock().unwrap();
+                    let success = noise_psk.is_some() && req.key.len() == NOISE_PSK_LEN;
+                    if success {
+                        *noise_psk = Some(BASE64_STANDARD.encode(&req.key));
+                    }
+                    success
+                };
+                self.send_msg(MessageType::NoiseEncryptionSetKeyResponse, &api::NoiseEncryptionSetKeyResponse { success }).await?;
+            }
+            MessageType::PingResponse | MessageType::GetTimeResponse => {}
+            _ => {
+                shared.received.lock().unwrap().push_back((msg_type, msg));
+                shared.received_notify.notify_waiters();
+            }
+        }
+        Ok(())
+    }
+}
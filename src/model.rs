@@ -1,5 +1,6 @@
-use crate::api;
+use crate::{api, error::DeviceError};
 use bytes::Bytes;
+use std::{collections::HashMap, sync::Arc};
 use strum_macros::{Display, FromRepr};
 use thiserror::Error;
 
@@ -16,12 +17,21 @@ pub enum LogLevel {
     VeryVerbose = 7,
 }
 
+#[derive(Debug, Clone)]
 pub struct Log {
     pub level: LogLevel,
     pub message: Bytes,
     pub send_failed: bool,
 }
 
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    Disconnected,
+    Connected,
+    /// connection was unexpectedly lost (the reconnect policy, if set, will try to reconnect)
+    Lost(Arc<DeviceError>),
+}
+
 #[derive(FromRepr, Display, Debug, PartialEq, Clone)]
 #[repr(i32)]
 pub enum UserServiceArgType {
@@ -81,7 +91,62 @@ impl TryFrom<api::ListEntitiesServicesResponse> for UserService {
     }
 }
 
-#[derive(FromRepr, Display, Debug, PartialEq, Clone)]
+/// API (protocol) version, compared as `major.minor`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// Version this client implements (sent in HelloRequest)
+pub const API_VERSION: ApiVersion = ApiVersion::new(1, 10);
+
+impl ApiVersion {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl std::fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HomeassistantCallType {
+    /// `homeassistant.service` (or `homeassistant.action`)
+    Service,
+    /// `homeassistant.event`
+    Event,
+}
+
+/// A service call or event the device wants the client (acting as Home Assistant) to perform
+#[derive(Debug, Clone)]
+pub struct HomeassistantServiceCall {
+    pub typ: HomeassistantCallType,
+    /// service (ex. `light.turn_on`) or event name (ex. `esphome.button_pressed`)
+    pub service: String,
+    pub data: HashMap<String, String>,
+    /// values are templates to be rendered with `variables`
+    pub data_template: HashMap<String, String>,
+    pub variables: HashMap<String, String>,
+}
+
+impl From<api::HomeassistantServiceResponse> for HomeassistantServiceCall {
+    fn from(value: api::HomeassistantServiceResponse) -> Self {
+        let to_map = |map: Vec<api::HomeassistantServiceMap>| map.into_iter().map(|m| (m.key, m.value)).collect();
+        Self {
+            typ: if value.is_event { HomeassistantCallType::Event } else { HomeassistantCallType::Service },
+            service: value.service,
+            data: to_map(value.data),
+            data_template: to_map(value.data_template),
+            variables: to_map(value.variables),
+        }
+    }
+}
+
+#[derive(FromRepr, Display, Debug, PartialEq, Eq, Hash, Clone)]
 #[repr(u16)]
 pub enum MessageType {
     HelloRequest = 1,
@@ -207,6 +272,8 @@ pub enum MessageType {
     VoiceAssistantConfigurationRequest = 121,
     VoiceAssistantConfigurationResponse = 122,
     VoiceAssistantSetConfiguration = 123,
+    NoiseEncryptionSetKeyRequest = 124,
+    NoiseEncryptionSetKeyResponse = 125,
 }
 
 
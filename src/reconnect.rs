@@ -0,0 +1,98 @@
+use rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// How (and if) a device reconnects after losing its connection.
+/// The delay before attempt `n` is `initial_delay * multiplier^(n-1)`, capped at
+/// `max_delay`, then randomly scaled by `1 ± jitter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// fraction (0.0 - 1.0) of the delay to randomly add or remove
+    pub jitter: f64,
+    /// give up after this many failed attempts (`None` retries forever)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// delay to wait before retrying after `attempt` (starting at 1) failed attempts
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let base = self.initial_delay.as_secs_f64() * exp;
+        let base = base.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let scale = 1.0 - jitter + 2.0 * jitter * random_unit();
+        // base is capped, but the jitter can still push it past what a Duration holds
+        Duration::try_from_secs_f64((base * scale).max(0.0)).unwrap_or(self.max_delay)
+    }
+
+    /// true if no more attempts should be made after `attempts` failed attempts
+    pub fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+/// random number in [0, 1)
+fn random_unit() -> f64 {
+    (OsRng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::device::ESPHomeDevice;
+    use crate::model::ConnectionState;
+    use crate::subscription::OverflowPolicy;
+    use crate::test_util::*;
+
+    #[test]
+    fn delay() {
+        let policy = ReconnectPolicy { jitter: 0.0, ..Default::default() };
+        assert_eq!(policy.delay(1), policy.initial_delay);
+        assert_eq!(policy.delay(u32::MAX), policy.max_delay);
+
+        // jitter on top of the largest delay doesn't overflow
+        let policy = ReconnectPolicy { max_delay: Duration::MAX, jitter: 1.0, ..Default::default() };
+        for _ in 0..100 {
+            policy.delay(u32::MAX);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_drop() {
+        let mock = mock_builder().noise_psk(NOISE_PSK).start().await.unwrap();
+        let dev = ESPHomeDevice::new_noise(mock.addr(), NOISE_PSK.to_string())
+            .with_reconnect_policy(ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                ..Default::default()
+            });
+        let handle = dev.spawn();
+        timeout(TIMEOUT, handle.connect()).await.unwrap().unwrap();
+        let mut rx = timeout(TIMEOUT, handle.subscribe_states(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+        timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        let mut state = handle.watch_connection_state().await.unwrap();
+
+        mock.disconnect_clients();
+        timeout(TIMEOUT, state.wait_for(|s| matches!(s, ConnectionState::Lost(_)))).await.unwrap().unwrap();
+        timeout(TIMEOUT, state.wait_for(|s| matches!(s, ConnectionState::Connected))).await.unwrap().unwrap();
+
+        //subscription was re-issued on the new connection
+        let update = timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap();
+        assert_eq!(update.entity_key, 1);
+        assert_eq!(handle.call(|dev| Box::pin(async move { dev.entities.light.len() })).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use thiserror::Error;
+use crate::{api, compat::SIGNED_INT_API_VERSION, device::ESPHomeDevice, error::DeviceError, handle::DeviceHandle, model::{ApiVersion, UserService, UserServiceArgType}};
+
+/// Value of a user service argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServiceArgValue {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+    String(String),
+    BoolArray(Vec<bool>),
+    IntArray(Vec<i32>),
+    FloatArray(Vec<f32>),
+    StringArray(Vec<String>),
+}
+
+/// Arguments by name (see `args!`)
+pub type ServiceArgs = HashMap<String, ServiceArgValue>;
+
+impl ServiceArgValue {
+    pub fn typ(&self) -> UserServiceArgType {
+        match self {
+            Self::Bool(_) => UserServiceArgType::Bool,
+            Self::Int(_) => UserServiceArgType::Int,
+            Self::Float(_) => UserServiceArgType::Float,
+            Self::String(_) => UserServiceArgType::String,
+            Self::BoolArray(_) => UserServiceArgType::BoolArray,
+            Self::IntArray(_) => UserServiceArgType::IntArray,
+            Self::FloatArray(_) => UserServiceArgType::FloatArray,
+            Self::StringArray(_) => UserServiceArgType::StringArray,
+        }
+    }
+
+    fn into_argument(self, api_version: Option<ApiVersion>) -> api::ExecuteServiceArgument {
+        let mut arg = api::ExecuteServiceArgument::default();
+        match self {
+            Self::Bool(v) => arg.bool = v,
+            Self::Int(v) if api_version.is_some_and(|v| v < SIGNED_INT_API_VERSION) => arg.legacy_int = v,
+            Self::Int(v) => arg.int = v,
+            Self::Float(v) => arg.float = v,
+            Self::String(v) => arg.string = v,
+            Self::BoolArray(v) => arg.bool_array = v,
+            Self::IntArray(v) => arg.int_array = v,
+            Self::FloatArray(v) => arg.float_array = v,
+            Self::StringArray(v) => arg.string_array = v,
+        }
+        arg
+    }
+}
+
+macro_rules! impl_from_arg {
+    ($($variant:ident($typ:ty) $(as $target:ty)?),* $(,)?) => {
+        $(
+            impl From<$typ> for ServiceArgValue {
+                fn from(value: $typ) -> Self {
+                    Self::$variant(value $(as $target)?)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_arg!(Bool(bool), Int(i32), Float(f32), Float(f64) as f32, String(String), BoolArray(Vec<bool>), IntArray(Vec<i32>), FloatArray(Vec<f32>), StringArray(Vec<String>));
+
+impl From<&str> for ServiceArgValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<Vec<&str>> for ServiceArgValue {
+    fn from(value: Vec<&str>) -> Self {
+        Self::StringArray(value.into_iter().map(str::to_string).collect())
+    }
+}
+
+/// Build `ServiceArgs` for `call_service`: `args! { "zone" => 2, "duration" => 1.5, "days" => vec!["mon", "fri"] }`
+#[macro_export]
+macro_rules! args {
+    () => { $crate::service::ServiceArgs::new() };
+    ($($name:expr => $value:expr),+ $(,)?) => {{
+        let mut args = $crate::service::ServiceArgs::new();
+        $(args.insert($name.to_string(), $crate::service::ServiceArgValue::from($value));)+
+        args
+    }};
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ServiceCallError {
+    #[error("no user service named `{0}`")]
+    UnknownService(String),
+    #[error("`{service}` has no argument `{arg}`")]
+    UnknownArg { service: String, arg: String },
+    #[error("`{service}` is missing argument `{arg}`")]
+    MissingArg { service: String, arg: String },
+    #[error("`{service}` argument `{arg}` should be {expected}, got {got}")]
+    WrongArgType { service: String, arg: String, expected: UserServiceArgType, got: UserServiceArgType },
+}
+
+/// Order `args` like the service declares them, checking names and types
+fn build_request(service: &UserService, mut args: ServiceArgs, api_version: Option<ApiVersion>) -> Result<api::ExecuteServiceRequest, ServiceCallError> {
+    if let Some(arg) = args.keys().find(|name| !service.args.iter().any(|a| &a.name == *name)) {
+        return Err(ServiceCallError::UnknownArg { service: service.name.clone(), arg: arg.clone() });
+    }
+    let mut req = api::ExecuteServiceRequest { key: service.key, args: Vec::with_capacity(service.args.len()) };
+    for declared in &service.args {
+        let value = args.remove(&declared.name).ok_or_else(|| ServiceCallError::MissingArg {
+            service: service.name.clone(),
+            arg: declared.name.clone(),
+        })?;
+        if value.typ() != declared.typ {
+            return Err(ServiceCallError::WrongArgType {
+                service: service.name.clone(),
+                arg: declared.name.clone(),
+                expected: declared.typ.clone(),
+                got: value.typ(),
+            });
+        }
+        req.args.push(value.into_argument(api_version));
+    }
+    Ok(req)
+}
+
+impl ESPHomeDevice {
+    pub fn get_service(&self, name: &str) -> Option<&UserService> {
+        self.services.values().find(|s| s.name == name)
+    }
+
+    /// Execute the user service (`api: services:`) named `name` with `args` (see `args!`)
+    pub async fn call_service(&mut self, name: &str, args: ServiceArgs) -> Result<(), DeviceError> {
+        let service = self.get_service(name).ok_or_else(|| ServiceCallError::UnknownService(name.to_string()))?;
+        let req = build_request(service, args, self.api_version)?;
+        self.execute_service(&req).await
+    }
+}
+
+impl DeviceHandle {
+    /// see `ESPHomeDevice::call_service`
+    pub async fn call_service(&self, name: &str, args: ServiceArgs) -> Result<(), DeviceError> {
+        let name = name.to_string();
+        self.call(move |dev| Box::pin(async move { dev.call_service(&name, args).await })).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::model::{MessageType, UserServiceArg};
+    use crate::test_util::*;
+
+    fn service() -> UserService {
+        UserService {
+            name: "set_schedule".to_string(),
+            key: 7,
+            args: vec![
+                UserServiceArg { name: "zone".to_string(), typ: UserServiceArgType::Int },
+                UserServiceArg { name: "minutes".to_string(), typ: UserServiceArgType::Float },
+                UserServiceArg { name: "days".to_string(), typ: UserServiceArgType::StringArray },
+            ],
+        }
+    }
+
+    #[test]
+    fn request() {
+        let args = args! { "days" => vec!["mon", "fri"], "zone" => -2, "minutes" => 1.5 };
+        let req = build_request(&service(), args.clone(), Some(ApiVersion::new(1, 10))).unwrap();
+        assert_eq!(req.key, 7);
+        assert_eq!((req.args[0].int, req.args[0].legacy_int), (-2, 0));
+        assert_eq!(req.args[1].float, 1.5);
+        assert_eq!(req.args[2].string_array, ["mon", "fri"]);
+
+        let legacy = build_request(&service(), args, Some(ApiVersion::new(1, 2))).unwrap();
+        assert_eq!((legacy.args[0].int, legacy.args[0].legacy_int), (0, -2));
+    }
+
+    #[test]
+    fn mismatches() {
+        let err = build_request(&service(), args! { "zone" => 1, "minutes" => 1.0 }, None).unwrap_err();
+        assert_eq!(err, ServiceCallError::MissingArg { service: "set_schedule".to_string(), arg: "days".to_string() });
+
+        let err = build_request(&service(), args! { "zone" => 1, "minutes" => 1.0, "days" => vec!["mon"], "x" => true }, None).unwrap_err();
+        assert!(matches!(err, ServiceCallError::UnknownArg { arg, .. } if arg == "x"));
+
+        let err = build_request(&service(), args! { "zone" => "1", "minutes" => 1.0, "days" => vec!["mon"] }, None).unwrap_err();
+        assert!(matches!(err, ServiceCallError::WrongArgType { expected: UserServiceArgType::Int, got: UserServiceArgType::String, .. }));
+    }
+
+    #[tokio::test]
+    async fn call_service() {
+        let (mock, mut dev) = connected_plain(mock_builder()
+            .entity(MessageType::ListEntitiesServicesResponse, &api::ListEntitiesServicesResponse {
+                name: "set_schedule".to_string(),
+                key: 9,
+                args: vec![
+                    api::ListEntitiesServicesArgument { name: "zone".to_string(), r#type: 1 },
+                    api::ListEntitiesServicesArgument { name: "enabled".to_string(), r#type: 0 },
+                ],
+            })
+        ).await;
+        assert!(matches!(dev.call_service("set_schedule", args! { "zone" => 3 }).await, Err(DeviceError::ServiceCall(_))));
+        timeout(TIMEOUT, dev.call_service("set_schedule", args! { "zone" => -3, "enabled" => true })).await.unwrap().unwrap();
+        let req: api::ExecuteServiceRequest = timeout(TIMEOUT, mock.wait_for(MessageType::ExecuteServiceRequest)).await.unwrap().unwrap();
+        assert_eq!(req.key, 9);
+        assert_eq!((req.args[0].int, req.args[1].bool), (-3, true));
+    }
+}
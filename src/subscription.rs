@@ -0,0 +1,258 @@
+use futures::{task::AtomicWaker, Stream, StreamExt};
+use std::{
+    collections::VecDeque, pin::Pin, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}, task::{Context, Poll}
+};
+use tokio::sync::Notify;
+
+/// What to do when a subscriber's buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// drop the oldest buffered item to make room
+    #[default]
+    DropOldest,
+    /// drop the new item
+    DropNewest,
+    /// wait until the subscriber makes room (stalls reading from the device)
+    Block,
+}
+
+type Filter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+struct Shared<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// only items passing the filter are sent to this subscriber
+    filter: Option<Filter<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    /// subscription was dropped
+    closed: AtomicBool,
+    /// fanout was dropped
+    sender_closed: AtomicBool,
+    recv_waker: AtomicWaker,
+    space: Notify,
+}
+
+/// Stream of items (ex. state updates or logs) from a device.
+/// Ends when the device is dropped. Dropping this only removes this subscriber.
+pub struct Subscription<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Sends every item to all subscribers, each with their own buffer and overflow policy
+pub(crate) struct Fanout<T> {
+    subscribers: Vec<Arc<Shared<T>>>,
+}
+
+impl<T> Default for Fanout<T> {
+    fn default() -> Self {
+        Self { subscribers: Vec::new() }
+    }
+}
+
+impl<T: Clone> Fanout<T> {
+    /// Add a subscriber with a buffer of `capacity` (at least 1) items
+    pub fn subscribe(&mut self, capacity: usize, policy: OverflowPolicy) -> Subscription<T> {
+        self.subscribe_inner(capacity, policy, None)
+    }
+
+    /// Add a subscriber which only gets items matching `filter`
+    pub fn subscribe_filtered(
+        &mut self,
+        capacity: usize,
+        policy: OverflowPolicy,
+        filter: impl Fn(&T) -> bool + Send + Sync + 'static
+    ) -> Subscription<T> {
+        self.subscribe_inner(capacity, policy, Some(Box::new(filter)))
+    }
+
+    fn subscribe_inner(&mut self, capacity: usize, policy: OverflowPolicy, filter: Option<Filter<T>>) -> Subscription<T> {
+        let capacity = capacity.max(1);
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            filter,
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            sender_closed: AtomicBool::new(false),
+            recv_waker: AtomicWaker::new(),
+            space: Notify::new(),
+        });
+        self.subscribers.push(shared.clone());
+        Subscription { shared }
+    }
+
+    /// true if any subscriber is still alive
+    pub fn has_subscribers(&mut self) -> bool {
+        self.subscribers.retain(|s| !s.closed.load(Ordering::Acquire));
+        !self.subscribers.is_empty()
+    }
+
+    pub async fn send(&mut self, item: T) {
+        self.subscribers.retain(|s| !s.closed.load(Ordering::Acquire));
+        for sub in &self.subscribers {
+            if let Some(filter) = &sub.filter && !filter(&item) {
+                continue;
+            }
+            loop {
+                let space = sub.space.notified();
+                {
+                    let mut queue = sub.queue.lock().unwrap();
+                    if queue.len() < sub.capacity {
+                        queue.push_back(item.clone());
+                        break;
+                    }
+                    match sub.policy {
+                        OverflowPolicy::DropOldest => {
+                            queue.pop_front();
+                            queue.push_back(item.clone());
+                            sub.dropped.fetch_add(1, Ordering::Relaxed);
+                            break;
+                        }
+                        OverflowPolicy::DropNewest => {
+                            sub.dropped.fetch_add(1, Ordering::Relaxed);
+                            break;
+                        }
+                        OverflowPolicy::Block => {}
+                    }
+                }
+                if sub.closed.load(Ordering::Acquire) {
+                    break;
+                }
+                space.await;
+            }
+            sub.recv_waker.wake();
+        }
+    }
+}
+
+impl<T> Fanout<T> {
+    /// End all subscriptions
+    pub fn close(&mut self) {
+        for sub in self.subscribers.drain(..) {
+            sub.sender_closed.store(true, Ordering::Release);
+            sub.recv_waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Fanout<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Subscription<T> {
+    /// Wait for the next item (None once the device is dropped)
+    pub async fn recv(&mut self) -> Option<T> {
+        self.next().await
+    }
+
+    /// Number of items dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let shared = &self.shared;
+        shared.recv_waker.register(cx.waker());
+        if let Some(item) = shared.queue.lock().unwrap().pop_front() {
+            shared.space.notify_one();
+            return Poll::Ready(Some(item));
+        }
+        if shared.sender_closed.load(Ordering::Acquire) {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use super::*;
+    use crate::api;
+    use crate::entity::EntityStateUpdateValue;
+    use crate::model::MessageType;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let mut fanout = Fanout::default();
+        let mut oldest = fanout.subscribe(2, OverflowPolicy::DropOldest);
+        let mut newest = fanout.subscribe(2, OverflowPolicy::DropNewest);
+        for i in 0..4 {
+            fanout.send(i).await;
+        }
+        assert_eq!((oldest.recv().await, oldest.recv().await), (Some(2), Some(3)));
+        assert_eq!((newest.recv().await, newest.recv().await), (Some(0), Some(1)));
+        assert_eq!((oldest.dropped(), newest.dropped()), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_space() {
+        let mut fanout = Fanout::default();
+        let mut sub = fanout.subscribe(1, OverflowPolicy::Block);
+        fanout.send(0).await;
+        assert!(timeout(Duration::from_millis(20), fanout.send(1)).await.is_err());
+        assert_eq!(sub.recv().await, Some(0));
+        timeout(Duration::from_secs(1), fanout.send(2)).await.unwrap();
+        assert_eq!(sub.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn dropped_subscriber_is_removed() {
+        let mut fanout = Fanout::default();
+        let blocked = fanout.subscribe(1, OverflowPolicy::Block);
+        let mut other = fanout.subscribe(4, OverflowPolicy::DropOldest);
+        fanout.send(0).await;
+        drop(blocked);
+        timeout(Duration::from_secs(1), fanout.send(1)).await.unwrap();
+        assert!(fanout.has_subscribers());
+        assert_eq!((other.recv().await, other.recv().await), (Some(0), Some(1)));
+        drop(fanout);
+        assert_eq!(other.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn filtered() {
+        let mut fanout = Fanout::default();
+        let mut even = fanout.subscribe_filtered(1, OverflowPolicy::Block, |i: &i32| i % 2 == 0);
+        for i in 1..=3 {
+            timeout(Duration::from_secs(1), fanout.send(i)).await.unwrap();
+        }
+        assert_eq!(even.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn independent_subscribers() {
+        let (mock, handle) = spawned_noise(mock_builder()).await;
+
+        let mut first = timeout(TIMEOUT, handle.subscribe_states(8, OverflowPolicy::DropOldest)).await.unwrap().unwrap();
+        let second = timeout(TIMEOUT, handle.subscribe_states(1, OverflowPolicy::Block)).await.unwrap().unwrap();
+        timeout(TIMEOUT, first.recv()).await.unwrap().unwrap();
+        drop(second);
+
+        for state in [true, false] {
+            mock.push(MessageType::SwitchStateResponse, &api::SwitchStateResponse { key: 2, state });
+        }
+        for state in [true, false] {
+            let update = timeout(TIMEOUT, first.recv()).await.unwrap().unwrap();
+            assert!(matches!(update.value, EntityStateUpdateValue::Switch(ref s) if s.state == state));
+        }
+        assert!(handle.is_connected().await.unwrap());
+    }
+}
@@ -0,0 +1,62 @@
+//! Shared setup for the tests talking to a `MockDevice`
+use std::time::Duration;
+use tokio::time::timeout;
+use crate::{api, device::ESPHomeDevice, handle::DeviceHandle, mock::{MockDevice, MockDeviceBuilder}, model::MessageType};
+
+pub(crate) const NOISE_PSK: &str = "GwsvILrvcN/BHAG9m7Hgzcqzc4Dx9neT/1RfEDmsecw=";
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A light, a switch and a camera (with the light on)
+pub(crate) fn mock_builder() -> MockDeviceBuilder {
+    MockDevice::builder()
+        .name("mock_bulb")
+        .entity(MessageType::ListEntitiesLightResponse, &api::ListEntitiesLightResponse {
+            object_id: "rgbct_bulb".to_string(),
+            key: 1,
+            name: "RGBCT Bulb".to_string(),
+            ..Default::default()
+        })
+        .entity(MessageType::ListEntitiesSwitchResponse, &api::ListEntitiesSwitchResponse {
+            object_id: "relay".to_string(),
+            key: 2,
+            ..Default::default()
+        })
+        .entity(MessageType::ListEntitiesCameraResponse, &api::ListEntitiesCameraResponse {
+            object_id: "doorbell".to_string(),
+            key: 3,
+            ..Default::default()
+        })
+        .state(MessageType::LightStateResponse, &api::LightStateResponse {
+            key: 1,
+            state: true,
+            ..Default::default()
+        })
+}
+
+/// Start the mock and connect to it over plaintext
+pub(crate) async fn connected_plain(builder: MockDeviceBuilder) -> (MockDevice, ESPHomeDevice) {
+    let mock = builder.start().await.unwrap();
+    let mut dev = ESPHomeDevice::new_plain(mock.addr(), String::new());
+    timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+    (mock, dev)
+}
+
+/// Start the mock with `NOISE_PSK` and connect to it over noise
+pub(crate) async fn connected_noise(builder: MockDeviceBuilder) -> (MockDevice, ESPHomeDevice) {
+    let mock = builder.noise_psk(NOISE_PSK).start().await.unwrap();
+    let mut dev = ESPHomeDevice::new_noise(mock.addr(), NOISE_PSK.to_string());
+    timeout(TIMEOUT, dev.connect()).await.unwrap().unwrap();
+    (mock, dev)
+}
+
+/// `connected_plain`, then spawn the device
+pub(crate) async fn spawned_plain(builder: MockDeviceBuilder) -> (MockDevice, DeviceHandle) {
+    let (mock, dev) = connected_plain(builder).await;
+    (mock, dev.spawn())
+}
+
+/// `connected_noise`, then spawn the device
+pub(crate) async fn spawned_noise(builder: MockDeviceBuilder) -> (MockDevice, DeviceHandle) {
+    let (mock, dev) = connected_noise(builder).await;
+    (mock, dev.spawn())
+}
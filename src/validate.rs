@@ -0,0 +1,306 @@
+use thiserror::Error;
+use crate::{api, device::ESPHomeDevice, entity::EntityType};
+
+/// Why a command was rejected before being sent (see `ESPHomeDevice::with_command_validation`)
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum InvalidCommand {
+    #[error("no {0} entity with key `{1}`")]
+    UnknownEntity(EntityType, u32),
+    #[error("`{field}` is not supported by this entity")]
+    Unsupported { field: &'static str },
+    #[error("`{field}` value `{value}` is not supported (supported: {supported:?})")]
+    UnsupportedValue { field: &'static str, value: String, supported: Vec<String> },
+    #[error("`{field}` value `{value}` is outside of {min}..={max}")]
+    OutOfRange { field: &'static str, value: f32, min: f32, max: f32 },
+    #[error("`{field}` value `{value}` is not a finite number")]
+    NotFinite { field: &'static str, value: f32 },
+    #[error("`{field}` value `{value}` is not a multiple of step `{step}` from `{min}`")]
+    NotOnStep { field: &'static str, value: f32, min: f32, step: f32 },
+}
+
+/// Checks a command against the entity it targets (as advertised in `ESPHomeDevice::entities`)
+pub trait ValidateCommand {
+    fn validate(&self, _dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        Ok(())
+    }
+}
+
+fn check_range(field: &'static str, value: f32, min: f32, max: f32) -> Result<(), InvalidCommand> {
+    check_finite(field, value)?;
+    if value < min || value > max {
+        return Err(InvalidCommand::OutOfRange { field, value, min, max });
+    }
+    Ok(())
+}
+
+/// NaN and infinities compare false against any bound, so they are rejected up front
+fn check_finite(field: &'static str, value: f32) -> Result<(), InvalidCommand> {
+    if !value.is_finite() {
+        return Err(InvalidCommand::NotFinite { field, value });
+    }
+    Ok(())
+}
+
+/// only checks the bounds if the entity advertises a valid range (max > min)
+fn check_visual_range(field: &'static str, value: f32, min: f32, max: f32) -> Result<(), InvalidCommand> {
+    check_finite(field, value)?;
+    if max > min {
+        check_range(field, value, min, max)?;
+    }
+    Ok(())
+}
+
+fn check_step(field: &'static str, value: f32, min: f32, step: f32) -> Result<(), InvalidCommand> {
+    if step > 0.0 {
+        let steps = (value - min) / step;
+        if (steps - steps.round()).abs() > 1e-3 {
+            return Err(InvalidCommand::NotOnStep { field, value, min, step });
+        }
+    }
+    Ok(())
+}
+
+fn check_supported<T: PartialEq + ToString>(field: &'static str, value: T, supported: &[T]) -> Result<(), InvalidCommand> {
+    if !supported.contains(&value) {
+        return Err(InvalidCommand::UnsupportedValue {
+            field,
+            value: value.to_string(),
+            supported: supported.iter().map(|v| v.to_string()).collect(),
+        });
+    }
+    Ok(())
+}
+
+/// enum values are sent as i32 but listed as their enum type
+fn check_supported_enum<T: std::fmt::Debug>(field: &'static str, value: i32, supported: &[i32]) -> Result<(), InvalidCommand>
+where i32: TryInto<T> {
+    if !supported.contains(&value) {
+        let name = |v: i32| v.try_into().map(|e: T| format!("{e:?}")).unwrap_or_else(|_| v.to_string());
+        return Err(InvalidCommand::UnsupportedValue {
+            field,
+            value: name(value),
+            supported: supported.iter().map(|v| name(*v)).collect(),
+        });
+    }
+    Ok(())
+}
+
+impl ValidateCommand for api::LightCommandRequest {
+    fn validate(&self, dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        let info = dev.get_light_from_key(&self.key)
+            .ok_or(InvalidCommand::UnknownEntity(EntityType::Light, self.key))?;
+        if self.has_color_mode {
+            check_supported("color_mode", self.color_mode, &info.supported_color_modes)?;
+        }
+        for (has, field, value) in [
+            (self.has_brightness, "brightness", self.brightness),
+            (self.has_color_brightness, "color_brightness", self.color_brightness),
+            (self.has_rgb, "red", self.red),
+            (self.has_rgb, "green", self.green),
+            (self.has_rgb, "blue", self.blue),
+            (self.has_white, "white", self.white),
+            (self.has_cold_white, "cold_white", self.cold_white),
+            (self.has_warm_white, "warm_white", self.warm_white),
+        ] {
+            if has {
+                check_range(field, value, 0.0, 1.0)?;
+            }
+        }
+        if self.has_color_temperature {
+            check_visual_range("color_temperature", self.color_temperature, info.min_mireds, info.max_mireds)?;
+        }
+        // "None" turns the effect off
+        if self.has_effect && !self.effect.eq_ignore_ascii_case("none") {
+            check_supported("effect", self.effect.as_str(), &info.effects.iter().map(String::as_str).collect::<Vec<_>>())?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidateCommand for api::ClimateCommandRequest {
+    fn validate(&self, dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        let info = dev.get_climate_from_key(&self.key)
+            .ok_or(InvalidCommand::UnknownEntity(EntityType::Climate, self.key))?;
+        if self.has_mode {
+            check_supported_enum::<api::ClimateMode>("mode", self.mode, &info.supported_modes)?;
+        }
+        for (has, field, value) in [
+            (self.has_target_temperature, "target_temperature", self.target_temperature),
+            (self.has_target_temperature_low, "target_temperature_low", self.target_temperature_low),
+            (self.has_target_temperature_high, "target_temperature_high", self.target_temperature_high),
+        ] {
+            if has {
+                check_visual_range(field, value, info.visual_min_temperature, info.visual_max_temperature)?;
+            }
+        }
+        if (self.has_target_temperature_low || self.has_target_temperature_high) && !info.supports_two_point_target_temperature {
+            return Err(InvalidCommand::Unsupported { field: "target_temperature_low/high" });
+        }
+        if self.has_target_humidity {
+            if !info.supports_target_humidity {
+                return Err(InvalidCommand::Unsupported { field: "target_humidity" });
+            }
+            check_visual_range("target_humidity", self.target_humidity, info.visual_min_humidity, info.visual_max_humidity)?;
+        }
+        if self.has_fan_mode {
+            check_supported_enum::<api::ClimateFanMode>("fan_mode", self.fan_mode, &info.supported_fan_modes)?;
+        }
+        if self.has_swing_mode {
+            check_supported_enum::<api::ClimateSwingMode>("swing_mode", self.swing_mode, &info.supported_swing_modes)?;
+        }
+        if self.has_preset {
+            check_supported_enum::<api::ClimatePreset>("preset", self.preset, &info.supported_presets)?;
+        }
+        if self.has_custom_fan_mode {
+            check_supported("custom_fan_mode", &self.custom_fan_mode, &info.supported_custom_fan_modes.iter().collect::<Vec<_>>())?;
+        }
+        if self.has_custom_preset {
+            check_supported("custom_preset", &self.custom_preset, &info.supported_custom_presets.iter().collect::<Vec<_>>())?;
+        }
+        Ok(())
+    }
+}
+
+impl ValidateCommand for api::NumberCommandRequest {
+    fn validate(&self, dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        let info = dev.get_number_from_key(&self.key)
+            .ok_or(InvalidCommand::UnknownEntity(EntityType::Number, self.key))?;
+        check_range("state", self.state, info.min_value, info.max_value)?;
+        check_step("state", self.state, info.min_value, info.step)
+    }
+}
+
+impl ValidateCommand for api::SelectCommandRequest {
+    fn validate(&self, dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        let info = dev.get_select_from_key(&self.key)
+            .ok_or(InvalidCommand::UnknownEntity(EntityType::Select, self.key))?;
+        check_supported("state", &self.state, &info.options.iter().collect::<Vec<_>>())
+    }
+}
+
+impl ValidateCommand for api::FanCommandRequest {
+    fn validate(&self, dev: &ESPHomeDevice) -> Result<(), InvalidCommand> {
+        let info = dev.get_fan_from_key(&self.key)
+            .ok_or(InvalidCommand::UnknownEntity(EntityType::Fan, self.key))?;
+        if self.has_speed_level {
+            if !info.supports_speed {
+                return Err(InvalidCommand::Unsupported { field: "speed_level" });
+            }
+            check_range("speed_level", self.speed_level as f32, 0.0, info.supported_speed_levels as f32)?;
+        }
+        if self.has_oscillating && !info.supports_oscillation {
+            return Err(InvalidCommand::Unsupported { field: "oscillating" });
+        }
+        if self.has_direction && !info.supports_direction {
+            return Err(InvalidCommand::Unsupported { field: "direction" });
+        }
+        if self.has_preset_mode {
+            check_supported("preset_mode", &self.preset_mode, &info.supported_preset_modes.iter().collect::<Vec<_>>())?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! no_validation {
+    ($($command:ident),*) => { paste::paste! {
+        $(impl ValidateCommand for api::[<$command CommandRequest>] {})*
+    }}
+}
+
+no_validation! {
+    Cover, Switch, Siren, Lock, Button, MediaPlayer,
+    AlarmControlPanel, Text, Date, Time, DateTime,
+    Valve, Update
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use prost::Message;
+    use crate::{error::DeviceError, model::MessageType};
+    use super::*;
+
+    fn device() -> ESPHomeDevice {
+        let mut dev = ESPHomeDevice::new_plain("127.0.0.1:0".to_string(), String::new()).with_command_validation();
+        let mut save = |msg_type, msg: &dyn Fn(&mut BytesMut)| {
+            let mut bytes = BytesMut::new();
+            msg(&mut bytes);
+            dev.save_entity(msg_type, bytes).unwrap();
+        };
+        save(MessageType::ListEntitiesLightResponse, &|b| api::ListEntitiesLightResponse {
+            key: 1,
+            supported_color_modes: vec![11, 35],
+            min_mireds: 153.0,
+            max_mireds: 500.0,
+            effects: vec!["Rainbow".to_string()],
+            ..Default::default()
+        }.encode(b).unwrap());
+        save(MessageType::ListEntitiesNumberResponse, &|b| api::ListEntitiesNumberResponse {
+            key: 2, min_value: 0.0, max_value: 10.0, step: 0.5, ..Default::default()
+        }.encode(b).unwrap());
+        save(MessageType::ListEntitiesSelectResponse, &|b| api::ListEntitiesSelectResponse {
+            key: 3, options: vec!["low".to_string(), "high".to_string()], ..Default::default()
+        }.encode(b).unwrap());
+        save(MessageType::ListEntitiesFanResponse, &|b| api::ListEntitiesFanResponse {
+            key: 4, supports_speed: true, supported_speed_levels: 3, ..Default::default()
+        }.encode(b).unwrap());
+        save(MessageType::ListEntitiesClimateResponse, &|b| api::ListEntitiesClimateResponse {
+            key: 5,
+            supported_modes: vec![api::ClimateMode::Off.into(), api::ClimateMode::Heat.into()],
+            visual_min_temperature: 10.0,
+            visual_max_temperature: 30.0,
+            ..Default::default()
+        }.encode(b).unwrap());
+        dev
+    }
+
+    #[test]
+    fn light() {
+        let dev = device();
+        let ok = api::LightCommandRequest { key: 1, has_color_mode: true, color_mode: 35, has_effect: true, effect: "None".to_string(), ..Default::default() };
+        assert_eq!(ok.validate(&dev), Ok(()));
+        let bad_mode = api::LightCommandRequest { key: 1, has_color_mode: true, color_mode: 7, ..Default::default() };
+        assert!(matches!(bad_mode.validate(&dev), Err(InvalidCommand::UnsupportedValue { field: "color_mode", .. })));
+        let bad_temp = api::LightCommandRequest { key: 1, has_color_temperature: true, color_temperature: 100.0, ..Default::default() };
+        assert!(matches!(bad_temp.validate(&dev), Err(InvalidCommand::OutOfRange { field: "color_temperature", .. })));
+        let nan_temp = api::LightCommandRequest { key: 1, has_color_temperature: true, color_temperature: f32::NAN, ..Default::default() };
+        assert!(matches!(nan_temp.validate(&dev), Err(InvalidCommand::NotFinite { field: "color_temperature", .. })));
+        let nan_brightness = api::LightCommandRequest { key: 1, has_brightness: true, brightness: f32::NAN, ..Default::default() };
+        assert!(matches!(nan_brightness.validate(&dev), Err(InvalidCommand::NotFinite { field: "brightness", .. })));
+        let bad_effect = api::LightCommandRequest { key: 1, has_effect: true, effect: "Strobe".to_string(), ..Default::default() };
+        assert!(matches!(bad_effect.validate(&dev), Err(InvalidCommand::UnsupportedValue { field: "effect", .. })));
+        let unknown = api::LightCommandRequest { key: 9, ..Default::default() };
+        assert_eq!(unknown.validate(&dev), Err(InvalidCommand::UnknownEntity(EntityType::Light, 9)));
+    }
+
+    #[test]
+    fn number_select_fan_climate() {
+        let dev = device();
+        assert_eq!(api::NumberCommandRequest { key: 2, state: 7.5 }.validate(&dev), Ok(()));
+        assert!(matches!(api::NumberCommandRequest { key: 2, state: 11.0 }.validate(&dev), Err(InvalidCommand::OutOfRange { .. })));
+        assert!(matches!(api::NumberCommandRequest { key: 2, state: 7.3 }.validate(&dev), Err(InvalidCommand::NotOnStep { .. })));
+
+        assert_eq!(api::SelectCommandRequest { key: 3, state: "high".to_string() }.validate(&dev), Ok(()));
+        assert!(matches!(api::SelectCommandRequest { key: 3, state: "max".to_string() }.validate(&dev), Err(InvalidCommand::UnsupportedValue { .. })));
+
+        let fan = api::FanCommandRequest { key: 4, has_speed_level: true, speed_level: 4, ..Default::default() };
+        assert!(matches!(fan.validate(&dev), Err(InvalidCommand::OutOfRange { field: "speed_level", .. })));
+        let fan = api::FanCommandRequest { key: 4, has_oscillating: true, ..Default::default() };
+        assert_eq!(fan.validate(&dev), Err(InvalidCommand::Unsupported { field: "oscillating" }));
+
+        let climate = api::ClimateCommandRequest { key: 5, has_mode: true, mode: api::ClimateMode::Cool.into(), ..Default::default() };
+        assert!(matches!(climate.validate(&dev), Err(InvalidCommand::UnsupportedValue { field: "mode", ref value, .. }) if value == "Cool"));
+        let climate = api::ClimateCommandRequest { key: 5, has_target_temperature: true, target_temperature: 21.0, ..Default::default() };
+        assert_eq!(climate.validate(&dev), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn command_rejected_before_sending() {
+        let mut dev = device();
+        let res = dev.number_command(&api::NumberCommandRequest { key: 2, state: 20.0 }).await;
+        assert!(matches!(res, Err(DeviceError::InvalidCommand(InvalidCommand::OutOfRange { .. }))));
+        // valid commands get as far as the (missing) connection
+        let res = dev.number_command(&api::NumberCommandRequest { key: 2, state: 2.0 }).await;
+        assert!(matches!(res, Err(DeviceError::ConnectionError(_))));
+    }
+}
@@ -0,0 +1,392 @@
+use bytes::{Bytes, BytesMut};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use crate::{
+    api, connection::options::{with_timeout, TimeoutStage}, device::{reply, ESPHomeDevice}, error::DeviceError, handle::DeviceHandle, model::MessageType,
+    subscription::{Fanout, OverflowPolicy, Subscription}
+};
+
+/// `SubscribeVoiceAssistantRequest.flags`: audio is sent over the API connection (instead of UDP)
+pub const VOICE_ASSISTANT_SUBSCRIBE_API_AUDIO: u32 = 1;
+
+/// `VoiceAssistantRequest.flags`: the device wants the server to detect the end of speech
+pub const VOICE_ASSISTANT_REQUEST_USE_VAD: u32 = 1;
+/// `VoiceAssistantRequest.flags`: the server should detect the wake word itself
+pub const VOICE_ASSISTANT_REQUEST_USE_WAKE_WORD: u32 = 2;
+
+/// A pipeline run the device asked for (before being attached to a handle)
+pub(crate) struct VoiceSessionStart {
+    req: api::VoiceAssistantRequest,
+    audio: Subscription<Bytes>,
+}
+
+/// Pipeline runs requested by the device (one at a time)
+pub struct VoiceAssistantSessions {
+    rx: mpsc::Receiver<VoiceSessionStart>,
+    handle: DeviceHandle,
+}
+
+impl VoiceAssistantSessions {
+    /// Wait for the device to start a pipeline (ex. after its wake word or a button press)
+    pub async fn next(&mut self) -> Option<VoiceSession> {
+        let start = self.rx.recv().await?;
+        Some(VoiceSession {
+            conversation_id: start.req.conversation_id,
+            flags: start.req.flags,
+            wake_word_phrase: (!start.req.wake_word_phrase.is_empty()).then_some(start.req.wake_word_phrase),
+            audio_settings: start.req.audio_settings,
+            audio: start.audio,
+            handle: self.handle.clone(),
+        })
+    }
+}
+
+/// One pipeline run: microphone audio in, events and (optionally) TTS audio out
+pub struct VoiceSession {
+    pub conversation_id: String,
+    /// `VOICE_ASSISTANT_REQUEST_*` bitflags
+    pub flags: u32,
+    /// set if the device detected the wake word itself
+    pub wake_word_phrase: Option<String>,
+    pub audio_settings: Option<api::VoiceAssistantAudioSettings>,
+    /// microphone audio (16-bit mono PCM at 16kHz), ends once the device stops streaming
+    pub audio: Subscription<Bytes>,
+    handle: DeviceHandle,
+}
+
+impl VoiceSession {
+    pub fn handle(&self) -> &DeviceHandle {
+        &self.handle
+    }
+
+    /// Report pipeline progress (ex. `VoiceAssistantSttEnd` with `("text", ..)`, `VoiceAssistantTtsEnd` with `("url", ..)`)
+    pub async fn event(&self, event: api::VoiceAssistantEvent, data: &[(&str, &str)]) -> Result<(), DeviceError> {
+        let res = api::VoiceAssistantEventResponse {
+            event_type: event.into(),
+            data: data.iter().map(|(name, value)| api::VoiceAssistantEventData {
+                name: name.to_string(),
+                value: value.to_string(),
+            }).collect(),
+        };
+        self.handle.call(move |dev| Box::pin(async move {
+            dev.send(MessageType::VoiceAssistantEventResponse, &res).await
+        })).await?
+    }
+
+    /// Fail the run, the device shows `message`
+    pub async fn error(&self, code: &str, message: &str) -> Result<(), DeviceError> {
+        self.event(api::VoiceAssistantEvent::VoiceAssistantError, &[("code", code), ("message", message)]).await
+    }
+
+    /// Stream TTS audio to the device (after `VoiceAssistantTtsStreamStart`)
+    pub async fn send_audio(&self, data: &[u8]) -> Result<(), DeviceError> {
+        let audio = api::VoiceAssistantAudio { data: data.to_vec(), end: false };
+        self.handle.call(move |dev| Box::pin(async move {
+            dev.send(MessageType::VoiceAssistantAudio, &audio).await
+        })).await?
+    }
+
+    pub async fn end_audio(&self) -> Result<(), DeviceError> {
+        self.handle.call(|dev| Box::pin(async move {
+            dev.send(MessageType::VoiceAssistantAudio, &api::VoiceAssistantAudio { data: vec![], end: true }).await
+        })).await?
+    }
+}
+
+/// A timer shown by the satellite (ex. set through an intent)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoiceAssistantTimer {
+    pub id: String,
+    pub name: String,
+    pub total: Duration,
+    pub left: Duration,
+    /// false while paused
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WakeWord {
+    pub id: String,
+    pub wake_word: String,
+    pub trained_languages: Vec<String>,
+}
+
+/// Wake words the device can listen for and which are enabled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VoiceAssistantConfiguration {
+    pub available_wake_words: Vec<WakeWord>,
+    /// ids of the enabled wake words
+    pub active_wake_words: Vec<String>,
+    pub max_active_wake_words: u32,
+}
+
+impl From<api::VoiceAssistantConfigurationResponse> for VoiceAssistantConfiguration {
+    fn from(value: api::VoiceAssistantConfigurationResponse) -> Self {
+        Self {
+            available_wake_words: value.available_wake_words.into_iter().map(|w| WakeWord {
+                id: w.id,
+                wake_word: w.wake_word,
+                trained_languages: w.trained_languages,
+            }).collect(),
+            active_wake_words: value.active_wake_words,
+            max_active_wake_words: value.max_active_wake_words,
+        }
+    }
+}
+
+impl ESPHomeDevice {
+    /// Start, update, cancel or finish a timer on the satellite
+    pub async fn voice_assistant_timer_event(&mut self, event: api::VoiceAssistantTimerEvent, timer: &VoiceAssistantTimer) -> Result<(), DeviceError> {
+        self.send(MessageType::VoiceAssistantTimerEventResponse, &api::VoiceAssistantTimerEventResponse {
+            event_type: event.into(),
+            timer_id: timer.id.clone(),
+            name: timer.name.clone(),
+            total_seconds: timer.total.as_secs() as u32,
+            seconds_left: timer.left.as_secs() as u32,
+            is_active: timer.active,
+        }).await
+    }
+
+    pub(crate) async fn request_voice_assistant_announce(&mut self, media_id: &str, text: &str) -> Result<oneshot::Receiver<BytesMut>, DeviceError> {
+        self.request(
+            MessageType::VoiceAssistantAnnounceRequest,
+            &api::VoiceAssistantAnnounceRequest { media_id: media_id.to_string(), text: text.to_string() },
+            MessageType::VoiceAssistantAnnounceFinished,
+        ).await
+    }
+
+    /// Play `media_id` (a URL) on the satellite, waiting until it finished. Returns false if it failed.
+    /// Announcements can be long, so `timeout` (`None` waits forever) replaces the connection's read and request timeouts.
+    pub async fn voice_assistant_announce(&mut self, media_id: &str, text: &str, timeout: Option<Duration>) -> Result<bool, DeviceError> {
+        let res: api::VoiceAssistantAnnounceFinished = with_timeout(timeout, TimeoutStage::Request, async {
+            let rx = self.request_voice_assistant_announce(media_id, text).await?;
+            self.wait_for_slow_reply(rx).await
+        }).await?;
+        Ok(res.success)
+    }
+
+    pub(crate) async fn request_voice_assistant_configuration(&mut self) -> Result<oneshot::Receiver<BytesMut>, DeviceError> {
+        self.request(
+            MessageType::VoiceAssistantConfigurationRequest,
+            &api::VoiceAssistantConfigurationRequest {},
+            MessageType::VoiceAssistantConfigurationResponse,
+        ).await
+    }
+
+    pub async fn voice_assistant_configuration(&mut self) -> Result<VoiceAssistantConfiguration, DeviceError> {
+        let res: api::VoiceAssistantConfigurationResponse = with_timeout(self.request_timeout(), TimeoutStage::Request, async {
+            let rx = self.request_voice_assistant_configuration().await?;
+            self.wait_for_reply(rx).await
+        }).await?;
+        Ok(res.into())
+    }
+
+    /// Enable wake words by id (see `VoiceAssistantConfiguration::available_wake_words`)
+    pub async fn set_voice_assistant_wake_words(&mut self, active_wake_words: &[&str]) -> Result<(), DeviceError> {
+        self.send(MessageType::VoiceAssistantSetConfiguration, &api::VoiceAssistantSetConfiguration {
+            active_wake_words: active_wake_words.iter().map(|w| w.to_string()).collect(),
+        }).await
+    }
+
+    pub(crate) async fn subscribe_voice_assistant(&mut self, audio_buffer_size: usize) -> Result<mpsc::Receiver<VoiceSessionStart>, DeviceError> {
+        let (tx, rx) = mpsc::channel(1);
+        self.voice_session_tx = Some(tx);
+        self.voice_audio_buffer_size = audio_buffer_size;
+        self.send(MessageType::SubscribeVoiceAssistantRequest, &api::SubscribeVoiceAssistantRequest {
+            subscribe: true,
+            flags: VOICE_ASSISTANT_SUBSCRIBE_API_AUDIO,
+        }).await?;
+        Ok(rx)
+    }
+
+    /// Stop handling pipeline runs for the device
+    pub async fn unsubscribe_voice_assistant(&mut self) -> Result<(), DeviceError> {
+        self.voice_session_tx = None;
+        self.voice_audio_tx = None;
+        self.send(MessageType::SubscribeVoiceAssistantRequest, &api::SubscribeVoiceAssistantRequest {
+            subscribe: false,
+            flags: 0,
+        }).await
+    }
+
+    /// start or stop a session, answering the device (audio goes over the API, so port 0)
+    pub(crate) async fn on_voice_assistant_request(&mut self, req: api::VoiceAssistantRequest) -> Result<(), DeviceError> {
+        self.voice_audio_tx = None;
+        if !req.start {
+            return Ok(());
+        }
+        let mut audio_tx = Fanout::default();
+        let audio = audio_tx.subscribe(self.voice_audio_buffer_size, OverflowPolicy::DropOldest);
+        let accepted = self.voice_session_tx.as_ref()
+            .is_some_and(|tx| tx.try_send(VoiceSessionStart { req, audio }).is_ok());
+        if accepted {
+            self.voice_audio_tx = Some(audio_tx);
+        }
+        self.send(MessageType::VoiceAssistantResponse, &api::VoiceAssistantResponse { port: 0, error: !accepted }).await
+    }
+
+    pub(crate) async fn on_voice_assistant_audio(&mut self, audio: api::VoiceAssistantAudio) {
+        if let Some(tx) = &mut self.voice_audio_tx {
+            if !audio.data.is_empty() {
+                tx.send(audio.data.into()).await;
+            }
+            if audio.end {
+                self.voice_audio_tx = None;
+            }
+        }
+    }
+}
+
+impl DeviceHandle {
+    /// Act as the voice assistant pipeline (wake word, STT, TTS) for the device.
+    /// Each session buffers up to `audio_buffer_size` audio chunks (dropping the oldest when full).
+    /// Runs requested while the previous session hasn't been taken with `next` are refused.
+    pub async fn subscribe_voice_assistant(&self, audio_buffer_size: usize) -> Result<VoiceAssistantSessions, DeviceError> {
+        let rx = self.call(move |dev| Box::pin(dev.subscribe_voice_assistant(audio_buffer_size))).await??;
+        Ok(VoiceAssistantSessions { rx, handle: self.clone() })
+    }
+
+    pub async fn unsubscribe_voice_assistant(&self) -> Result<(), DeviceError> {
+        self.call(|dev| Box::pin(dev.unsubscribe_voice_assistant())).await?
+    }
+
+    /// Start, update, cancel or finish a timer on the satellite
+    pub async fn voice_assistant_timer_event(&self, event: api::VoiceAssistantTimerEvent, timer: &VoiceAssistantTimer) -> Result<(), DeviceError> {
+        let timer = timer.clone();
+        self.call(move |dev| Box::pin(async move {
+            dev.voice_assistant_timer_event(event, &timer).await
+        })).await?
+    }
+
+    /// Play `media_id` (a URL) on the satellite, waiting until it finished. Returns false if it failed.
+    /// Announcements can be long, so `timeout` (`None` waits forever) replaces the connection's request timeout.
+    pub async fn voice_assistant_announce(&self, media_id: &str, text: &str, timeout: Option<Duration>) -> Result<bool, DeviceError> {
+        let (media_id, text) = (media_id.to_string(), text.to_string());
+        let rx = self.call(move |dev| Box::pin(async move {
+            dev.request_voice_assistant_announce(&media_id, &text).await
+        })).await??;
+        let res: api::VoiceAssistantAnnounceFinished = with_timeout(timeout, TimeoutStage::Request, reply(rx)).await?;
+        Ok(res.success)
+    }
+
+    pub async fn voice_assistant_configuration(&self) -> Result<VoiceAssistantConfiguration, DeviceError> {
+        let (rx, request_timeout) = self.call(|dev| Box::pin(async move {
+            Ok::<_, DeviceError>((dev.request_voice_assistant_configuration().await?, dev.request_timeout()))
+        })).await??;
+        let res: api::VoiceAssistantConfigurationResponse = with_timeout(request_timeout, TimeoutStage::Request, reply(rx)).await?;
+        Ok(res.into())
+    }
+
+    /// Enable wake words by id (see `VoiceAssistantConfiguration::available_wake_words`)
+    pub async fn set_voice_assistant_wake_words(&self, active_wake_words: &[&str]) -> Result<(), DeviceError> {
+        let active_wake_words: Vec<String> = active_wake_words.iter().map(|w| w.to_string()).collect();
+        self.call(move |dev| Box::pin(async move {
+            let active_wake_words: Vec<&str> = active_wake_words.iter().map(String::as_str).collect();
+            dev.set_voice_assistant_wake_words(&active_wake_words).await
+        })).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+    use crate::test_util::*;
+
+    #[tokio::test]
+    async fn voice_assistant_pipeline() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let mut sessions = timeout(TIMEOUT, handle.subscribe_voice_assistant(16)).await.unwrap().unwrap();
+        let req: api::SubscribeVoiceAssistantRequest = timeout(TIMEOUT, mock.wait_for(MessageType::SubscribeVoiceAssistantRequest)).await.unwrap().unwrap();
+        assert!(req.subscribe);
+        assert_eq!(req.flags, 1);
+
+        mock.push(MessageType::VoiceAssistantRequest, &api::VoiceAssistantRequest {
+            start: true,
+            conversation_id: "abc".to_string(),
+            flags: 1,
+            wake_word_phrase: "okay nabu".to_string(),
+            ..Default::default()
+        });
+        let mut session = timeout(TIMEOUT, sessions.next()).await.unwrap().unwrap();
+        assert_eq!(session.conversation_id, "abc");
+        assert_eq!(session.wake_word_phrase.as_deref(), Some("okay nabu"));
+        let res: api::VoiceAssistantResponse = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantResponse)).await.unwrap().unwrap();
+        assert_eq!((res.port, res.error), (0, false));
+
+        //microphone audio until the device ends it
+        mock.push(MessageType::VoiceAssistantAudio, &api::VoiceAssistantAudio { data: vec![1, 2], end: false });
+        mock.push(MessageType::VoiceAssistantAudio, &api::VoiceAssistantAudio { data: vec![3, 4], end: false });
+        mock.push(MessageType::VoiceAssistantAudio, &api::VoiceAssistantAudio { data: vec![], end: true });
+        let mut audio = Vec::new();
+        while let Some(chunk) = timeout(TIMEOUT, session.audio.recv()).await.unwrap() {
+            audio.extend_from_slice(&chunk);
+        }
+        assert_eq!(audio, [1, 2, 3, 4]);
+
+        timeout(TIMEOUT, session.event(api::VoiceAssistantEvent::VoiceAssistantSttEnd, &[("text", "turn on the light")])).await.unwrap().unwrap();
+        let event: api::VoiceAssistantEventResponse = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantEventResponse)).await.unwrap().unwrap();
+        assert_eq!(event.event_type, i32::from(api::VoiceAssistantEvent::VoiceAssistantSttEnd));
+        assert_eq!((event.data[0].name.as_str(), event.data[0].value.as_str()), ("text", "turn on the light"));
+        timeout(TIMEOUT, session.send_audio(&[9; 4])).await.unwrap().unwrap();
+        timeout(TIMEOUT, session.end_audio()).await.unwrap().unwrap();
+        let tts: api::VoiceAssistantAudio = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantAudio)).await.unwrap().unwrap();
+        assert_eq!(tts.data, [9; 4]);
+        let tts: api::VoiceAssistantAudio = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantAudio)).await.unwrap().unwrap();
+        assert!(tts.end);
+
+        //refused once nobody takes sessions
+        drop(sessions);
+        mock.push(MessageType::VoiceAssistantRequest, &api::VoiceAssistantRequest { start: true, ..Default::default() });
+        let res: api::VoiceAssistantResponse = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantResponse)).await.unwrap().unwrap();
+        assert!(res.error);
+    }
+
+    #[tokio::test]
+    async fn voice_assistant_control() {
+        let (mock, handle) = spawned_plain(mock_builder()).await;
+
+        let timer = VoiceAssistantTimer {
+            id: "t1".to_string(),
+            name: "pasta".to_string(),
+            total: Duration::from_secs(600),
+            left: Duration::from_secs(590),
+            active: true,
+        };
+        timeout(TIMEOUT, handle.voice_assistant_timer_event(api::VoiceAssistantTimerEvent::VoiceAssistantTimerUpdated, &timer)).await.unwrap().unwrap();
+        let res: api::VoiceAssistantTimerEventResponse = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantTimerEventResponse)).await.unwrap().unwrap();
+        assert_eq!((res.timer_id.as_str(), res.total_seconds, res.seconds_left, res.is_active), ("t1", 600, 590, true));
+
+        let announce = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.voice_assistant_announce("http://tts/hello.mp3", "hello", Some(TIMEOUT)).await }
+        });
+        let req: api::VoiceAssistantAnnounceRequest = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantAnnounceRequest)).await.unwrap().unwrap();
+        assert_eq!(req.media_id, "http://tts/hello.mp3");
+        mock.push(MessageType::VoiceAssistantAnnounceFinished, &api::VoiceAssistantAnnounceFinished { success: true });
+        assert!(timeout(TIMEOUT, announce).await.unwrap().unwrap().unwrap());
+        //the device never finishes this one
+        let unfinished = handle.voice_assistant_announce("http://tts/long.mp3", "long", Some(Duration::from_millis(50)));
+        assert!(matches!(timeout(TIMEOUT, unfinished).await.unwrap(), Err(DeviceError::Timeout { stage: TimeoutStage::Request })));
+        let _: api::VoiceAssistantAnnounceRequest = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantAnnounceRequest)).await.unwrap().unwrap();
+
+        let config = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.voice_assistant_configuration().await }
+        });
+        let _: api::VoiceAssistantConfigurationRequest = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantConfigurationRequest)).await.unwrap().unwrap();
+        mock.push(MessageType::VoiceAssistantConfigurationResponse, &api::VoiceAssistantConfigurationResponse {
+            available_wake_words: vec![api::VoiceAssistantWakeWord { id: "okay_nabu".to_string(), wake_word: "Okay Nabu".to_string(), trained_languages: vec!["en".to_string()] }],
+            active_wake_words: vec![],
+            max_active_wake_words: 1,
+        });
+        let config = timeout(TIMEOUT, config).await.unwrap().unwrap().unwrap();
+        assert_eq!(config.available_wake_words[0].wake_word, "Okay Nabu");
+        assert_eq!(config.max_active_wake_words, 1);
+
+        timeout(TIMEOUT, handle.set_voice_assistant_wake_words(&["okay_nabu"])).await.unwrap().unwrap();
+        let req: api::VoiceAssistantSetConfiguration = timeout(TIMEOUT, mock.wait_for(MessageType::VoiceAssistantSetConfiguration)).await.unwrap().unwrap();
+        assert_eq!(req.active_wake_words, ["okay_nabu"]);
+    }
+}